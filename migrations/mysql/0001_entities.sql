@@ -0,0 +1,119 @@
+-- map tables in the rdm layout, only created with the rdm profile: golbat and rocketmap bring their own
+
+CREATE TABLE IF NOT EXISTS `gym` (
+  `id` varchar(35) NOT NULL,
+  `lat` double NOT NULL,
+  `lon` double NOT NULL,
+  `name` varchar(128) DEFAULT NULL,
+  `url` varchar(200) DEFAULT NULL,
+  `last_modified_timestamp` int unsigned DEFAULT NULL,
+  `raid_end_timestamp` int unsigned DEFAULT NULL,
+  `raid_spawn_timestamp` int unsigned DEFAULT NULL,
+  `raid_battle_timestamp` int unsigned DEFAULT NULL,
+  `updated` int unsigned NOT NULL,
+  `raid_pokemon_id` smallint unsigned DEFAULT NULL,
+  `guarding_pokemon_id` smallint unsigned DEFAULT NULL,
+  `availble_slots` smallint unsigned DEFAULT NULL,
+  `team_id` tinyint unsigned DEFAULT NULL,
+  `raid_level` tinyint unsigned DEFAULT NULL,
+  `enabled` tinyint unsigned DEFAULT NULL,
+  `ex_raid_eligible` tinyint unsigned DEFAULT NULL,
+  `in_battle` tinyint unsigned DEFAULT NULL,
+  `raid_pokemon_move_1` smallint unsigned DEFAULT NULL,
+  `raid_pokemon_move_2` smallint unsigned DEFAULT NULL,
+  `raid_pokemon_form` smallint unsigned DEFAULT NULL,
+  `raid_pokemon_cp` int unsigned DEFAULT NULL,
+  `raid_is_exclusive` tinyint unsigned DEFAULT NULL,
+  `first_seen_timestamp` int unsigned NOT NULL,
+  `raid_pokemon_gender` tinyint unsigned DEFAULT NULL,
+  `sponsor_id` smallint unsigned DEFAULT NULL,
+  `raid_pokemon_evolution` tinyint unsigned DEFAULT NULL,
+  `ar_scan_eligible` tinyint unsigned DEFAULT NULL,
+  PRIMARY KEY (`id`),
+  KEY `ix_coords` (`lat`, `lon`),
+  KEY `ix_updated` (`updated`)
+);
+
+CREATE TABLE IF NOT EXISTS `pokestop` (
+  `id` varchar(35) NOT NULL,
+  `lat` double NOT NULL,
+  `lon` double NOT NULL,
+  `name` varchar(128) DEFAULT NULL,
+  `url` varchar(200) DEFAULT NULL,
+  `lure_expire_timestamp` int unsigned DEFAULT NULL,
+  `last_modified_timestamp` int unsigned DEFAULT NULL,
+  `updated` int unsigned DEFAULT NULL,
+  `enabled` tinyint unsigned DEFAULT NULL,
+  `quest_type` int unsigned DEFAULT NULL,
+  `quest_timestamp` int unsigned DEFAULT NULL,
+  `quest_target` smallint unsigned DEFAULT NULL,
+  `quest_conditions` text,
+  `quest_rewards` text,
+  `quest_template` varchar(100) DEFAULT NULL,
+  `alternative_quest_type` int unsigned DEFAULT NULL,
+  `alternative_quest_timestamp` int unsigned DEFAULT NULL,
+  `alternative_quest_target` smallint unsigned DEFAULT NULL,
+  `alternative_quest_conditions` text,
+  `alternative_quest_rewards` text,
+  `alternative_quest_template` varchar(100) DEFAULT NULL,
+  `pokestop_display` smallint unsigned DEFAULT NULL,
+  `incident_expire_timestamp` int unsigned DEFAULT NULL,
+  `lure_id` smallint unsigned DEFAULT NULL,
+  `grunt_type` smallint unsigned DEFAULT NULL,
+  `first_seen_timestamp` int unsigned NOT NULL,
+  `ar_scan_eligible` tinyint unsigned DEFAULT NULL,
+  PRIMARY KEY (`id`),
+  KEY `ix_coords` (`lat`, `lon`),
+  KEY `ix_updated` (`updated`)
+);
+
+CREATE TABLE IF NOT EXISTS `pokemon` (
+  `id` varchar(25) NOT NULL,
+  `pokestop_id` varchar(35) DEFAULT NULL,
+  `spawn_id` bigint unsigned DEFAULT NULL,
+  `lat` double NOT NULL,
+  `lon` double NOT NULL,
+  `weight` double DEFAULT NULL,
+  `size` double DEFAULT NULL,
+  `expire_timestamp` int unsigned DEFAULT NULL,
+  `updated` int unsigned DEFAULT NULL,
+  `pokemon_id` smallint unsigned NOT NULL,
+  `move_1` smallint unsigned DEFAULT NULL,
+  `move_2` smallint unsigned DEFAULT NULL,
+  `gender` tinyint unsigned DEFAULT NULL,
+  `cp` smallint unsigned DEFAULT NULL,
+  `atk_iv` tinyint unsigned DEFAULT NULL,
+  `def_iv` tinyint unsigned DEFAULT NULL,
+  `sta_iv` tinyint unsigned DEFAULT NULL,
+  `form` smallint unsigned DEFAULT NULL,
+  `level` tinyint unsigned DEFAULT NULL,
+  `weather` tinyint unsigned DEFAULT NULL,
+  `costume` smallint unsigned DEFAULT NULL,
+  `first_seen_timestamp` int unsigned NOT NULL,
+  `cell_id` bigint unsigned DEFAULT NULL,
+  `expire_timestamp_verified` tinyint unsigned NOT NULL,
+  `capture_1` double DEFAULT NULL,
+  `capture_2` double DEFAULT NULL,
+  `capture_3` double DEFAULT NULL,
+  `shiny` tinyint unsigned DEFAULT NULL,
+  `username` varchar(32) DEFAULT NULL,
+  `display_pokemon_id` smallint unsigned DEFAULT NULL,
+  `is_event` tinyint unsigned NOT NULL DEFAULT 0,
+  `pvp_rankings_great_league` text,
+  `pvp_rankings_ultra_league` text,
+  PRIMARY KEY (`id`),
+  KEY `ix_coords` (`lat`, `lon`),
+  KEY `ix_expire_timestamp` (`expire_timestamp`)
+);
+
+CREATE TABLE IF NOT EXISTS `spawnpoint` (
+  `id` bigint unsigned NOT NULL,
+  `lat` double NOT NULL,
+  `lon` double NOT NULL,
+  `updated` int unsigned NOT NULL,
+  `last_seen` int unsigned NOT NULL,
+  `despawn_sec` smallint unsigned DEFAULT NULL,
+  PRIMARY KEY (`id`),
+  KEY `ix_coords` (`lat`, `lon`),
+  KEY `ix_last_seen` (`last_seen`)
+);
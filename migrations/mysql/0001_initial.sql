@@ -0,0 +1,79 @@
+CREATE TABLE IF NOT EXISTS `schema_version` (
+  `version` int unsigned NOT NULL,
+  `name` varchar(100) NOT NULL,
+  `applied_at` bigint NOT NULL,
+  PRIMARY KEY (`version`)
+);
+
+CREATE TABLE IF NOT EXISTS `pokestop_event` (
+  `pokestop_id` varchar(35) NOT NULL,
+  `event` varchar(16) NOT NULL,
+  `type_id` smallint unsigned NOT NULL,
+  `start` int unsigned NOT NULL,
+  `expire` int unsigned NOT NULL,
+  PRIMARY KEY (`pokestop_id`, `event`, `expire`),
+  KEY `ix_start` (`start`)
+);
+
+CREATE TABLE IF NOT EXISTS `pokemon_pvp` (
+  `encounter_id` varchar(25) NOT NULL,
+  `league` varchar(16) NOT NULL,
+  `pokemon_id` smallint unsigned NOT NULL,
+  `form` smallint unsigned DEFAULT NULL,
+  `rank` int unsigned DEFAULT NULL,
+  `cp` int unsigned DEFAULT NULL,
+  `level` double DEFAULT NULL,
+  `percentage` double DEFAULT NULL,
+  KEY `ix_encounter_id` (`encounter_id`),
+  KEY `ix_league_rank` (`league`, `rank`)
+);
+
+CREATE TABLE IF NOT EXISTS `pokemon_archive` (
+  `id` varchar(25) NOT NULL,
+  `pokemon_id` smallint unsigned NOT NULL,
+  `form` smallint unsigned DEFAULT NULL,
+  `lat` double NOT NULL,
+  `lon` double NOT NULL,
+  `expire_timestamp` int unsigned DEFAULT NULL,
+  `atk_iv` tinyint unsigned DEFAULT NULL,
+  `def_iv` tinyint unsigned DEFAULT NULL,
+  `sta_iv` tinyint unsigned DEFAULT NULL,
+  `cp` smallint unsigned DEFAULT NULL,
+  `level` tinyint unsigned DEFAULT NULL,
+  `shiny` tinyint unsigned DEFAULT NULL,
+  `data` text NOT NULL,
+  PRIMARY KEY (`id`)
+);
+
+CREATE TABLE IF NOT EXISTS `pokemon_stats` (
+  `date` date NOT NULL,
+  `pokemon_id` smallint unsigned NOT NULL,
+  `count` int unsigned NOT NULL,
+  PRIMARY KEY (`date`, `pokemon_id`)
+);
+
+CREATE TABLE IF NOT EXISTS `city` (
+  `id` smallint unsigned NOT NULL AUTO_INCREMENT,
+  `name` varchar(100) NOT NULL,
+  `coordinates` text NOT NULL,
+  `scadenza` bigint NOT NULL,
+  `monitor` tinyint unsigned NOT NULL,
+  `admins_users` text NOT NULL,
+  PRIMARY KEY (`id`)
+);
+
+CREATE TABLE IF NOT EXISTS `city_stats_today` (
+  `day` date NOT NULL,
+  `city_id` smallint unsigned NOT NULL,
+  `encounter_id` varchar(25) NOT NULL,
+  `pokemon_id` smallint unsigned NOT NULL,
+  PRIMARY KEY (`day`, `city_id`, `encounter_id`)
+);
+
+CREATE TABLE IF NOT EXISTS `city_lure_stats` (
+  `day` date NOT NULL,
+  `city_id` smallint unsigned NOT NULL,
+  `lure_id` smallint unsigned NOT NULL,
+  `count` int unsigned NOT NULL,
+  PRIMARY KEY (`day`, `city_id`, `lure_id`)
+);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS `city_entity_stats` (
+  `day` date NOT NULL,
+  `city_id` smallint unsigned NOT NULL,
+  `kind` varchar(16) NOT NULL,
+  `entity_id` varchar(64) NOT NULL,
+  `value` int NOT NULL,
+  PRIMARY KEY (`day`, `city_id`, `kind`, `entity_id`)
+);
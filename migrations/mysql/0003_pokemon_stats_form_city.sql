@@ -0,0 +1,31 @@
+-- MySQL can't roll DDL back nor add columns and keys only if missing,
+-- every change checks information_schema first so that an interrupted migration can run again
+SET @migration = IF(
+  (SELECT COUNT(*) FROM information_schema.columns
+    WHERE table_schema = DATABASE() AND table_name = 'pokemon_stats' AND column_name = 'form') = 0,
+  'ALTER TABLE `pokemon_stats` ADD COLUMN `form` smallint unsigned NOT NULL DEFAULT 0 AFTER `pokemon_id`',
+  'DO 0'
+);
+PREPARE migration FROM @migration;
+EXECUTE migration;
+DEALLOCATE PREPARE migration;
+
+SET @migration = IF(
+  (SELECT COUNT(*) FROM information_schema.columns
+    WHERE table_schema = DATABASE() AND table_name = 'pokemon_stats' AND column_name = 'city_id') = 0,
+  'ALTER TABLE `pokemon_stats` ADD COLUMN `city_id` smallint unsigned NOT NULL DEFAULT 0 AFTER `form`',
+  'DO 0'
+);
+PREPARE migration FROM @migration;
+EXECUTE migration;
+DEALLOCATE PREPARE migration;
+
+SET @migration = IF(
+  (SELECT COUNT(*) FROM information_schema.key_column_usage
+    WHERE table_schema = DATABASE() AND table_name = 'pokemon_stats' AND constraint_name = 'PRIMARY' AND column_name = 'city_id') = 0,
+  'ALTER TABLE `pokemon_stats` DROP PRIMARY KEY, ADD PRIMARY KEY (`date`, `pokemon_id`, `form`, `city_id`)',
+  'DO 0'
+);
+PREPARE migration FROM @migration;
+EXECUTE migration;
+DEALLOCATE PREPARE migration;
@@ -0,0 +1,11 @@
+-- IANA name like `Europe/Rome`, NULL uses the configured default
+-- the column is only added when missing, like in 0003, so that the migration can run again
+SET @migration = IF(
+  (SELECT COUNT(*) FROM information_schema.columns
+    WHERE table_schema = DATABASE() AND table_name = 'city' AND column_name = 'timezone') = 0,
+  'ALTER TABLE `city` ADD COLUMN `timezone` varchar(64) NULL DEFAULT NULL AFTER `admins_users`',
+  'DO 0'
+);
+PREPARE migration FROM @migration;
+EXECUTE migration;
+DEALLOCATE PREPARE migration;
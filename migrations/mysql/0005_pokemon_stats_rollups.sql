@@ -0,0 +1,35 @@
+-- `hour` is the unix timestamp the hour starts at
+CREATE TABLE IF NOT EXISTS `pokemon_stats_hourly` (
+  `hour` bigint NOT NULL,
+  `pokemon_id` smallint unsigned NOT NULL,
+  `form` smallint unsigned NOT NULL,
+  `city_id` smallint unsigned NOT NULL,
+  `count` int unsigned NOT NULL,
+  PRIMARY KEY (`hour`, `pokemon_id`, `form`, `city_id`)
+);
+
+-- `week` is its monday
+CREATE TABLE IF NOT EXISTS `pokemon_stats_weekly` (
+  `week` date NOT NULL,
+  `pokemon_id` smallint unsigned NOT NULL,
+  `form` smallint unsigned NOT NULL,
+  `city_id` smallint unsigned NOT NULL,
+  `count` int unsigned NOT NULL,
+  PRIMARY KEY (`week`, `pokemon_id`, `form`, `city_id`)
+);
+
+-- `month` is its first day
+CREATE TABLE IF NOT EXISTS `pokemon_stats_monthly` (
+  `month` date NOT NULL,
+  `pokemon_id` smallint unsigned NOT NULL,
+  `form` smallint unsigned NOT NULL,
+  `city_id` smallint unsigned NOT NULL,
+  `count` int unsigned NOT NULL,
+  PRIMARY KEY (`month`, `pokemon_id`, `form`, `city_id`)
+);
+
+-- days of `pokemon_stats` already added to the weekly and monthly tables
+CREATE TABLE IF NOT EXISTS `stats_compacted` (
+  `day` date NOT NULL,
+  PRIMARY KEY (`day`)
+);
@@ -0,0 +1,47 @@
+-- one ranking per pokemon and form of an encounter league, a missing form is stored as 0
+-- the table is rebuilt with its key only while pokemon_pvp has none, so that the migration can run again
+SET @pvp_key = (SELECT COUNT(*) FROM information_schema.key_column_usage
+  WHERE table_schema = DATABASE() AND table_name = 'pokemon_pvp' AND constraint_name = 'PRIMARY');
+
+SET @migration = IF(
+  @pvp_key = 0,
+  'CREATE TABLE IF NOT EXISTS `pokemon_pvp_new` (
+    `encounter_id` varchar(25) NOT NULL,
+    `league` varchar(16) NOT NULL,
+    `pokemon_id` smallint unsigned NOT NULL,
+    `form` smallint unsigned NOT NULL DEFAULT 0,
+    `rank` int unsigned DEFAULT NULL,
+    `cp` int unsigned DEFAULT NULL,
+    `level` double DEFAULT NULL,
+    `percentage` double DEFAULT NULL,
+    PRIMARY KEY (`encounter_id`, `league`, `pokemon_id`, `form`),
+    KEY `ix_league_rank` (`league`, `rank`)
+  )',
+  'DO 0'
+);
+PREPARE migration FROM @migration;
+EXECUTE migration;
+DEALLOCATE PREPARE migration;
+
+-- duplicates keep their best rank
+SET @migration = IF(
+  @pvp_key = 0,
+  'INSERT IGNORE INTO `pokemon_pvp_new`
+    SELECT `encounter_id`, `league`, `pokemon_id`, COALESCE(`form`, 0), `rank`, `cp`, `level`, `percentage`
+    FROM `pokemon_pvp` ORDER BY `rank` IS NULL, `rank`',
+  'DO 0'
+);
+PREPARE migration FROM @migration;
+EXECUTE migration;
+DEALLOCATE PREPARE migration;
+
+SET @migration = IF(
+  @pvp_key = 0,
+  'RENAME TABLE `pokemon_pvp` TO `pokemon_pvp_old`, `pokemon_pvp_new` TO `pokemon_pvp`',
+  'DO 0'
+);
+PREPARE migration FROM @migration;
+EXECUTE migration;
+DEALLOCATE PREPARE migration;
+
+DROP TABLE IF EXISTS `pokemon_pvp_old`;
@@ -0,0 +1,119 @@
+-- map tables in the rdm layout, only created with the rdm profile: golbat and rocketmap bring their own
+
+CREATE TABLE IF NOT EXISTS gym (
+    id varchar(35) NOT NULL PRIMARY KEY,
+    lat double precision NOT NULL,
+    lon double precision NOT NULL,
+    name varchar(128),
+    url varchar(200),
+    last_modified_timestamp bigint,
+    raid_end_timestamp bigint,
+    raid_spawn_timestamp bigint,
+    raid_battle_timestamp bigint,
+    updated bigint NOT NULL,
+    raid_pokemon_id integer,
+    guarding_pokemon_id integer,
+    availble_slots smallint,
+    team_id smallint,
+    raid_level smallint,
+    enabled boolean,
+    ex_raid_eligible boolean,
+    in_battle boolean,
+    raid_pokemon_move_1 integer,
+    raid_pokemon_move_2 integer,
+    raid_pokemon_form integer,
+    raid_pokemon_cp integer,
+    raid_is_exclusive boolean,
+    first_seen_timestamp bigint NOT NULL,
+    raid_pokemon_gender smallint,
+    sponsor_id integer,
+    raid_pokemon_evolution smallint,
+    ar_scan_eligible boolean
+);
+
+CREATE INDEX IF NOT EXISTS ix_gym_coords ON gym (lat, lon);
+CREATE INDEX IF NOT EXISTS ix_gym_updated ON gym (updated);
+
+CREATE TABLE IF NOT EXISTS pokestop (
+    id varchar(35) NOT NULL PRIMARY KEY,
+    lat double precision NOT NULL,
+    lon double precision NOT NULL,
+    name varchar(128),
+    url varchar(200),
+    lure_expire_timestamp bigint,
+    last_modified_timestamp bigint,
+    updated bigint,
+    enabled boolean,
+    quest_type integer,
+    quest_timestamp bigint,
+    quest_target integer,
+    quest_conditions text,
+    quest_rewards text,
+    quest_template varchar(100),
+    alternative_quest_type integer,
+    alternative_quest_timestamp bigint,
+    alternative_quest_target integer,
+    alternative_quest_conditions text,
+    alternative_quest_rewards text,
+    alternative_quest_template varchar(100),
+    pokestop_display integer,
+    incident_expire_timestamp bigint,
+    lure_id integer,
+    grunt_type integer,
+    first_seen_timestamp bigint NOT NULL,
+    ar_scan_eligible boolean
+);
+
+CREATE INDEX IF NOT EXISTS ix_pokestop_coords ON pokestop (lat, lon);
+CREATE INDEX IF NOT EXISTS ix_pokestop_updated ON pokestop (updated);
+
+CREATE TABLE IF NOT EXISTS pokemon (
+    id varchar(25) NOT NULL PRIMARY KEY,
+    pokestop_id varchar(35),
+    spawn_id bigint,
+    lat double precision NOT NULL,
+    lon double precision NOT NULL,
+    weight double precision,
+    size double precision,
+    expire_timestamp bigint,
+    updated bigint,
+    pokemon_id integer NOT NULL,
+    move_1 integer,
+    move_2 integer,
+    gender smallint,
+    cp integer,
+    atk_iv smallint,
+    def_iv smallint,
+    sta_iv smallint,
+    form integer,
+    level smallint,
+    weather smallint,
+    costume integer,
+    first_seen_timestamp bigint NOT NULL,
+    cell_id bigint,
+    expire_timestamp_verified boolean NOT NULL,
+    capture_1 double precision,
+    capture_2 double precision,
+    capture_3 double precision,
+    shiny boolean,
+    username varchar(32),
+    display_pokemon_id integer,
+    is_event boolean NOT NULL DEFAULT false,
+    pvp_rankings_great_league text,
+    pvp_rankings_ultra_league text
+);
+
+CREATE INDEX IF NOT EXISTS ix_pokemon_coords ON pokemon (lat, lon);
+CREATE INDEX IF NOT EXISTS ix_pokemon_expire_timestamp ON pokemon (expire_timestamp);
+
+CREATE TABLE IF NOT EXISTS spawnpoint (
+    id bigint NOT NULL PRIMARY KEY,
+    lat double precision NOT NULL,
+    lon double precision NOT NULL,
+    updated bigint NOT NULL,
+    last_seen bigint NOT NULL,
+    despawn_sec integer
+);
+
+CREATE INDEX IF NOT EXISTS ix_spawnpoint_coords ON spawnpoint (lat, lon);
+CREATE INDEX IF NOT EXISTS ix_spawnpoint_last_seen ON spawnpoint (last_seen);
@@ -0,0 +1,78 @@
+CREATE TABLE IF NOT EXISTS schema_version (
+    version integer NOT NULL PRIMARY KEY,
+    name varchar(100) NOT NULL,
+    applied_at bigint NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS pokestop_event (
+    pokestop_id varchar(35) NOT NULL,
+    event varchar(16) NOT NULL,
+    type_id integer NOT NULL,
+    start bigint NOT NULL,
+    expire bigint NOT NULL,
+    PRIMARY KEY (pokestop_id, event, expire)
+);
+
+CREATE INDEX IF NOT EXISTS ix_pokestop_event_start ON pokestop_event (start);
+
+CREATE TABLE IF NOT EXISTS pokemon_pvp (
+    encounter_id varchar(25) NOT NULL,
+    league varchar(16) NOT NULL,
+    pokemon_id integer NOT NULL,
+    form integer,
+    rank integer,
+    cp integer,
+    level double precision,
+    percentage double precision
+);
+
+CREATE INDEX IF NOT EXISTS ix_pokemon_pvp_encounter_id ON pokemon_pvp (encounter_id);
+CREATE INDEX IF NOT EXISTS ix_pokemon_pvp_league_rank ON pokemon_pvp (league, rank);
+
+CREATE TABLE IF NOT EXISTS pokemon_archive (
+    id varchar(25) NOT NULL PRIMARY KEY,
+    pokemon_id integer NOT NULL,
+    form integer,
+    lat double precision NOT NULL,
+    lon double precision NOT NULL,
+    expire_timestamp bigint,
+    atk_iv smallint,
+    def_iv smallint,
+    sta_iv smallint,
+    cp integer,
+    level smallint,
+    shiny boolean,
+    data text NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS pokemon_stats (
+    "date" date NOT NULL,
+    pokemon_id integer NOT NULL,
+    count integer NOT NULL,
+    PRIMARY KEY ("date", pokemon_id)
+);
+
+CREATE TABLE IF NOT EXISTS city (
+    id serial NOT NULL PRIMARY KEY,
+    name varchar(100) NOT NULL,
+    coordinates text NOT NULL,
+    scadenza bigint NOT NULL,
+    monitor smallint NOT NULL,
+    admins_users text NOT NULL DEFAULT ''
+);
+
+CREATE TABLE IF NOT EXISTS city_stats_today (
+    day date NOT NULL,
+    city_id integer NOT NULL,
+    encounter_id varchar(25) NOT NULL,
+    pokemon_id integer NOT NULL,
+    PRIMARY KEY (day, city_id, encounter_id)
+);
+
+CREATE TABLE IF NOT EXISTS city_lure_stats (
+    day date NOT NULL,
+    city_id integer NOT NULL,
+    lure_id integer NOT NULL,
+    count integer NOT NULL,
+    PRIMARY KEY (day, city_id, lure_id)
+);
@@ -0,0 +1,7 @@
+ALTER TABLE pokemon_stats
+    ADD COLUMN form integer NOT NULL DEFAULT 0,
+    ADD COLUMN city_id integer NOT NULL DEFAULT 0;
+
+ALTER TABLE pokemon_stats DROP CONSTRAINT pokemon_stats_pkey;
+
+ALTER TABLE pokemon_stats ADD PRIMARY KEY ("date", pokemon_id, form, city_id);
@@ -0,0 +1,2 @@
+-- IANA name like `Europe/Rome`, NULL uses the configured default
+ALTER TABLE city ADD COLUMN timezone varchar(64);
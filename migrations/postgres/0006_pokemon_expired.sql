@@ -0,0 +1,2 @@
+-- expired pokemon moved by the retention task, when keep_expired_pokemon is set
+CREATE TABLE IF NOT EXISTS pokemon_expired (LIKE pokemon INCLUDING ALL);
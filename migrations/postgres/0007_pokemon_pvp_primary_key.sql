@@ -0,0 +1,16 @@
+-- one ranking per pokemon and form of an encounter league, a missing form is stored as 0
+UPDATE pokemon_pvp SET form = 0 WHERE form IS NULL;
+
+-- duplicates keep their best rank
+DELETE FROM pokemon_pvp WHERE ctid NOT IN (
+    SELECT DISTINCT ON (encounter_id, league, pokemon_id, form) ctid FROM pokemon_pvp
+    ORDER BY encounter_id, league, pokemon_id, form, rank NULLS LAST
+);
+
+ALTER TABLE pokemon_pvp
+    ALTER COLUMN form SET DEFAULT 0,
+    ALTER COLUMN form SET NOT NULL,
+    ADD PRIMARY KEY (encounter_id, league, pokemon_id, form);
+
+-- the primary key starts with encounter_id
+DROP INDEX IF EXISTS ix_pokemon_pvp_encounter_id;
@@ -0,0 +1,107 @@
+-- map tables in the rdm layout, only created with the rdm profile: golbat and rocketmap bring their own
+
+CREATE TABLE IF NOT EXISTS gym (
+    id TEXT NOT NULL PRIMARY KEY,
+    lat REAL NOT NULL,
+    lon REAL NOT NULL,
+    name TEXT,
+    url TEXT,
+    last_modified_timestamp INTEGER,
+    raid_end_timestamp INTEGER,
+    raid_spawn_timestamp INTEGER,
+    raid_battle_timestamp INTEGER,
+    updated INTEGER NOT NULL,
+    raid_pokemon_id INTEGER,
+    guarding_pokemon_id INTEGER,
+    availble_slots INTEGER,
+    team_id INTEGER,
+    raid_level INTEGER,
+    enabled INTEGER,
+    ex_raid_eligible INTEGER,
+    in_battle INTEGER,
+    raid_pokemon_move_1 INTEGER,
+    raid_pokemon_move_2 INTEGER,
+    raid_pokemon_form INTEGER,
+    raid_pokemon_cp INTEGER,
+    raid_is_exclusive INTEGER,
+    first_seen_timestamp INTEGER NOT NULL,
+    raid_pokemon_gender INTEGER,
+    sponsor_id INTEGER,
+    raid_pokemon_evolution INTEGER,
+    ar_scan_eligible INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS pokestop (
+    id TEXT NOT NULL PRIMARY KEY,
+    lat REAL NOT NULL,
+    lon REAL NOT NULL,
+    name TEXT,
+    url TEXT,
+    lure_expire_timestamp INTEGER,
+    last_modified_timestamp INTEGER,
+    updated INTEGER,
+    enabled INTEGER,
+    quest_type INTEGER,
+    quest_timestamp INTEGER,
+    quest_target INTEGER,
+    quest_conditions TEXT,
+    quest_rewards TEXT,
+    quest_template TEXT,
+    alternative_quest_type INTEGER,
+    alternative_quest_timestamp INTEGER,
+    alternative_quest_target INTEGER,
+    alternative_quest_conditions TEXT,
+    alternative_quest_rewards TEXT,
+    alternative_quest_template TEXT,
+    pokestop_display INTEGER,
+    incident_expire_timestamp INTEGER,
+    lure_id INTEGER,
+    grunt_type INTEGER,
+    first_seen_timestamp INTEGER NOT NULL,
+    ar_scan_eligible INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS pokemon (
+    id TEXT NOT NULL PRIMARY KEY,
+    pokestop_id TEXT,
+    spawn_id INTEGER,
+    lat REAL NOT NULL,
+    lon REAL NOT NULL,
+    weight REAL,
+    size REAL,
+    expire_timestamp INTEGER,
+    updated INTEGER,
+    pokemon_id INTEGER NOT NULL,
+    move_1 INTEGER,
+    move_2 INTEGER,
+    gender INTEGER,
+    cp INTEGER,
+    atk_iv INTEGER,
+    def_iv INTEGER,
+    sta_iv INTEGER,
+    form INTEGER,
+    level INTEGER,
+    weather INTEGER,
+    costume INTEGER,
+    first_seen_timestamp INTEGER NOT NULL,
+    cell_id INTEGER,
+    expire_timestamp_verified INTEGER NOT NULL,
+    capture_1 REAL,
+    capture_2 REAL,
+    capture_3 REAL,
+    shiny INTEGER,
+    username TEXT,
+    display_pokemon_id INTEGER,
+    is_event INTEGER NOT NULL DEFAULT 0,
+    pvp_rankings_great_league TEXT,
+    pvp_rankings_ultra_league TEXT
+);
+
+CREATE TABLE IF NOT EXISTS spawnpoint (
+    id INTEGER NOT NULL PRIMARY KEY,
+    lat REAL NOT NULL,
+    lon REAL NOT NULL,
+    updated INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
+    despawn_sec INTEGER
+);
@@ -0,0 +1,75 @@
+CREATE TABLE IF NOT EXISTS schema_version (
+    version INTEGER NOT NULL PRIMARY KEY,
+    name TEXT NOT NULL,
+    applied_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS pokestop_event (
+    pokestop_id TEXT NOT NULL,
+    event TEXT NOT NULL,
+    type_id INTEGER NOT NULL,
+    start INTEGER NOT NULL,
+    expire INTEGER NOT NULL,
+    PRIMARY KEY (pokestop_id, event, expire)
+);
+
+CREATE TABLE IF NOT EXISTS pokemon_pvp (
+    encounter_id TEXT NOT NULL,
+    league TEXT NOT NULL,
+    pokemon_id INTEGER NOT NULL,
+    form INTEGER,
+    rank INTEGER,
+    cp INTEGER,
+    level REAL,
+    percentage REAL
+);
+
+CREATE INDEX IF NOT EXISTS ix_pokemon_pvp_encounter_id ON pokemon_pvp (encounter_id);
+
+CREATE TABLE IF NOT EXISTS pokemon_stats (
+    date TEXT NOT NULL,
+    pokemon_id INTEGER NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (date, pokemon_id)
+);
+
+CREATE TABLE IF NOT EXISTS city (
+    id INTEGER NOT NULL PRIMARY KEY,
+    name TEXT NOT NULL,
+    coordinates TEXT NOT NULL,
+    scadenza INTEGER NOT NULL,
+    monitor INTEGER NOT NULL,
+    admins_users TEXT NOT NULL DEFAULT ''
+);
+
+CREATE TABLE IF NOT EXISTS city_stats_today (
+    day TEXT NOT NULL,
+    city_id INTEGER NOT NULL,
+    encounter_id TEXT NOT NULL,
+    pokemon_id INTEGER NOT NULL,
+    PRIMARY KEY (day, city_id, encounter_id)
+);
+
+CREATE TABLE IF NOT EXISTS city_lure_stats (
+    day TEXT NOT NULL,
+    city_id INTEGER NOT NULL,
+    lure_id INTEGER NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (day, city_id, lure_id)
+);
+
+CREATE TABLE IF NOT EXISTS pokemon_archive (
+    id TEXT NOT NULL PRIMARY KEY,
+    pokemon_id INTEGER NOT NULL,
+    form INTEGER,
+    lat REAL NOT NULL,
+    lon REAL NOT NULL,
+    expire_timestamp INTEGER,
+    atk_iv INTEGER,
+    def_iv INTEGER,
+    sta_iv INTEGER,
+    cp INTEGER,
+    level INTEGER,
+    shiny INTEGER,
+    data TEXT NOT NULL
+);
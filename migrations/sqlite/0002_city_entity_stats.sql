@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS city_entity_stats (
+    day TEXT NOT NULL,
+    city_id INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    entity_id TEXT NOT NULL,
+    value INTEGER NOT NULL,
+    PRIMARY KEY (day, city_id, kind, entity_id)
+);
@@ -0,0 +1,15 @@
+-- SQLite can't change a primary key, the table is rebuilt
+CREATE TABLE pokemon_stats_new (
+    date TEXT NOT NULL,
+    pokemon_id INTEGER NOT NULL,
+    form INTEGER NOT NULL DEFAULT 0,
+    city_id INTEGER NOT NULL DEFAULT 0,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (date, pokemon_id, form, city_id)
+);
+
+INSERT INTO pokemon_stats_new (date, pokemon_id, count) SELECT date, pokemon_id, count FROM pokemon_stats;
+
+DROP TABLE pokemon_stats;
+
+ALTER TABLE pokemon_stats_new RENAME TO pokemon_stats;
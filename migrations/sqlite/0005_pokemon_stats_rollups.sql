@@ -0,0 +1,34 @@
+-- hour is the unix timestamp the hour starts at
+CREATE TABLE IF NOT EXISTS pokemon_stats_hourly (
+    hour INTEGER NOT NULL,
+    pokemon_id INTEGER NOT NULL,
+    form INTEGER NOT NULL,
+    city_id INTEGER NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (hour, pokemon_id, form, city_id)
+);
+
+-- week is its monday
+CREATE TABLE IF NOT EXISTS pokemon_stats_weekly (
+    week TEXT NOT NULL,
+    pokemon_id INTEGER NOT NULL,
+    form INTEGER NOT NULL,
+    city_id INTEGER NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (week, pokemon_id, form, city_id)
+);
+
+-- month is its first day
+CREATE TABLE IF NOT EXISTS pokemon_stats_monthly (
+    month TEXT NOT NULL,
+    pokemon_id INTEGER NOT NULL,
+    form INTEGER NOT NULL,
+    city_id INTEGER NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (month, pokemon_id, form, city_id)
+);
+
+-- days of pokemon_stats already added to the weekly and monthly tables
+CREATE TABLE IF NOT EXISTS stats_compacted (
+    day TEXT NOT NULL PRIMARY KEY
+);
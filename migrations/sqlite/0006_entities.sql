@@ -0,0 +1,36 @@
+-- same columns and constraints as `pokemon`, sqlite has no CREATE TABLE ... LIKE
+CREATE TABLE IF NOT EXISTS pokemon_expired (
+    id TEXT NOT NULL PRIMARY KEY,
+    pokestop_id TEXT,
+    spawn_id INTEGER,
+    lat REAL NOT NULL,
+    lon REAL NOT NULL,
+    weight REAL,
+    size REAL,
+    expire_timestamp INTEGER,
+    updated INTEGER,
+    pokemon_id INTEGER NOT NULL,
+    move_1 INTEGER,
+    move_2 INTEGER,
+    gender INTEGER,
+    cp INTEGER,
+    atk_iv INTEGER,
+    def_iv INTEGER,
+    sta_iv INTEGER,
+    form INTEGER,
+    level INTEGER,
+    weather INTEGER,
+    costume INTEGER,
+    first_seen_timestamp INTEGER NOT NULL,
+    cell_id INTEGER,
+    expire_timestamp_verified INTEGER NOT NULL,
+    capture_1 REAL,
+    capture_2 REAL,
+    capture_3 REAL,
+    shiny INTEGER,
+    username TEXT,
+    display_pokemon_id INTEGER,
+    is_event INTEGER NOT NULL DEFAULT 0,
+    pvp_rankings_great_league TEXT,
+    pvp_rankings_ultra_league TEXT
+);
@@ -0,0 +1,5 @@
+-- expired pokemon moved by the retention task, when keep_expired_pokemon is set
+-- the pokemon table of other profiles isn't hookedmap's, only its columns are copied
+CREATE TABLE IF NOT EXISTS pokemon_expired AS SELECT * FROM pokemon WHERE 0;
+
+CREATE UNIQUE INDEX IF NOT EXISTS ix_pokemon_expired_id ON pokemon_expired (id);
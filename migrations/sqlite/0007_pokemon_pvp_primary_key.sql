@@ -0,0 +1,21 @@
+-- one ranking per pokemon and form of an encounter league, a missing form is stored as 0
+-- sqlite can't add a primary key to an existing table, it is rebuilt
+CREATE TABLE pokemon_pvp_new (
+    encounter_id TEXT NOT NULL,
+    league TEXT NOT NULL,
+    pokemon_id INTEGER NOT NULL,
+    form INTEGER NOT NULL DEFAULT 0,
+    rank INTEGER,
+    cp INTEGER,
+    level REAL,
+    percentage REAL,
+    PRIMARY KEY (encounter_id, league, pokemon_id, form)
+);
+
+-- duplicates keep their best rank
+INSERT OR IGNORE INTO pokemon_pvp_new
+    SELECT encounter_id, league, pokemon_id, COALESCE(form, 0), rank, cp, level, percentage
+    FROM pokemon_pvp ORDER BY rank IS NULL, rank;
+
+DROP TABLE pokemon_pvp;
+ALTER TABLE pokemon_pvp_new RENAME TO pokemon_pvp;
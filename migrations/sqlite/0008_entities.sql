@@ -0,0 +1,43 @@
+-- pokemon_expired was copied from pokemon without its types, defaults and constraints, it is rebuilt
+CREATE TABLE pokemon_expired_new (
+    id TEXT NOT NULL PRIMARY KEY,
+    pokestop_id TEXT,
+    spawn_id INTEGER,
+    lat REAL NOT NULL,
+    lon REAL NOT NULL,
+    weight REAL,
+    size REAL,
+    expire_timestamp INTEGER,
+    updated INTEGER,
+    pokemon_id INTEGER NOT NULL,
+    move_1 INTEGER,
+    move_2 INTEGER,
+    gender INTEGER,
+    cp INTEGER,
+    atk_iv INTEGER,
+    def_iv INTEGER,
+    sta_iv INTEGER,
+    form INTEGER,
+    level INTEGER,
+    weather INTEGER,
+    costume INTEGER,
+    first_seen_timestamp INTEGER NOT NULL,
+    cell_id INTEGER,
+    expire_timestamp_verified INTEGER NOT NULL,
+    capture_1 REAL,
+    capture_2 REAL,
+    capture_3 REAL,
+    shiny INTEGER,
+    username TEXT,
+    display_pokemon_id INTEGER,
+    is_event INTEGER NOT NULL DEFAULT 0,
+    pvp_rankings_great_league TEXT,
+    pvp_rankings_ultra_league TEXT
+);
+
+-- the copy kept the columns of pokemon in the same order
+INSERT INTO pokemon_expired_new SELECT * FROM pokemon_expired;
+
+DROP TABLE pokemon_expired;
+ALTER TABLE pokemon_expired_new RENAME TO pokemon_expired;
+CREATE UNIQUE INDEX IF NOT EXISTS ix_pokemon_expired_id ON pokemon_expired (id);
@@ -0,0 +1 @@
+-- pokemon_expired is only declared again for rdm databases, whose pokemon table comes from hookedmap
@@ -0,0 +1,242 @@
+use std::fmt;
+
+use chrono::{NaiveDate, Utc};
+
+use hyper::{body, header, Body, Method, Request, Response, StatusCode};
+
+use serde::Serialize;
+
+use serde_json::Value;
+
+use sha2::{Digest, Sha256};
+
+use tracing::{error, info};
+
+use crate::{
+    config::Admin,
+    db::{reader, storage},
+    geometry,
+    lists::{self, City, CITIES},
+    stats,
+};
+
+// failures become responses only at the end, a response is too big to travel in every Result
+type Failure = (StatusCode, String);
+type Reply = Result<Response<Body>, Failure>;
+
+fn reply(status: StatusCode, body: impl Serialize) -> Response<Body> {
+    let mut response = Response::new(Body::from(serde_json::to_vec(&body).unwrap_or_default()));
+    *response.status_mut() = status;
+    response.headers_mut().insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/json"));
+    response
+}
+
+fn fail(status: StatusCode, message: impl Into<String>) -> Failure {
+    (status, message.into())
+}
+
+// tokens are compared through their digests in constant time, neither content nor length leak
+fn same_token(a: &str, b: &str) -> bool {
+    let (a, b) = (Sha256::digest(a), Sha256::digest(b));
+    a.iter().zip(b.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// who is calling, the global token manages every city, the others only the ones listing their user
+enum Caller<'a> {
+    Admin,
+    User(&'a str),
+}
+
+impl Caller<'_> {
+    fn new<'a>(req: &Request<Body>, admin: &'a Admin) -> Option<Caller<'a>> {
+        let token = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))?
+            .trim();
+        if admin.token.as_deref().is_some_and(|admin| same_token(admin, token)) {
+            return Some(Caller::Admin);
+        }
+        admin.users.iter().find(|(user_token, _)| same_token(user_token, token)).map(|(_, user)| Caller::User(user))
+    }
+
+    fn manages(&self, city: &City) -> bool {
+        match self {
+            Caller::Admin => true,
+            Caller::User(user) => city.admins_users.iter().any(|admin| admin == user),
+        }
+    }
+}
+
+impl fmt::Display for Caller<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Caller::Admin => write!(f, "admin"),
+            Caller::User(user) => write!(f, "user \"{}\"", user),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CityView<'a> {
+    id: u16,
+    name: &'a str,
+    /// WKT, longitude first
+    coordinates: String,
+    scadenza: i64,
+    active: bool,
+    monitor: u8,
+    admins_users: &'a [String],
+    timezone: Option<&'static str>,
+}
+
+impl<'a> From<&'a City> for CityView<'a> {
+    fn from(city: &'a City) -> Self {
+        CityView {
+            id: city.id,
+            name: &city.name,
+            coordinates: geometry::to_wkt(&city.coordinates),
+            scadenza: city.scadenza,
+            active: city.active(Utc::now().timestamp()),
+            monitor: city.scan_iv,
+            admins_users: &city.admins_users,
+            timezone: city.timezone.map(|timezone| timezone.name()),
+        }
+    }
+}
+
+/// Serves the `/admin/` endpoints
+pub async fn handle(req: Request<Body>, admin: &Admin) -> Response<Body> {
+    let Some(caller) = Caller::new(&req, admin) else {
+        return reply(StatusCode::UNAUTHORIZED, "missing or invalid token");
+    };
+
+    let path = req.uri().path().trim_matches('/').to_owned();
+    let segments: Vec<&str> = path.split('/').collect();
+    let result = match (req.method().clone(), segments.as_slice()) {
+        (Method::POST, ["admin", "cities", "reload"]) => reload(&caller).await,
+        (Method::GET, ["admin", "cities"]) => Ok(list(&caller)),
+        (Method::GET, ["admin", "cities", id]) => show(&caller, id),
+        (Method::GET, ["admin", "cities", id, "stats"]) => stats(&caller, id, &req).await,
+        (Method::PUT, ["admin", "cities", id, "coordinates"]) => coordinates(&caller, id, req).await,
+        (Method::PUT, ["admin", "cities", id, "monitor"]) => monitor(&caller, id, req).await,
+        _ => Err(fail(StatusCode::NOT_FOUND, "unknown endpoint")),
+    };
+    result.unwrap_or_else(|(status, message)| reply(status, message))
+}
+
+// the requested city id, if the caller manages it
+fn city_id(caller: &Caller<'_>, id: &str) -> Result<u16, Failure> {
+    let id = id.parse().map_err(|_| fail(StatusCode::BAD_REQUEST, "invalid city id"))?;
+    match CITIES.load().cities.get(&id) {
+        Some(city) if caller.manages(city) => Ok(id),
+        // cities managed by someone else look missing, to not tell which ids exist
+        _ => Err(fail(StatusCode::NOT_FOUND, "unknown city")),
+    }
+}
+
+async fn read_body(req: Request<Body>) -> Result<String, Failure> {
+    let bytes = body::to_bytes(req.into_body()).await.map_err(|e| {
+        error!("admin body error: {}", e);
+        fail(StatusCode::BAD_REQUEST, "unreadable body")
+    })?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| fail(StatusCode::BAD_REQUEST, "body isn't valid UTF-8"))
+}
+
+async fn reload(caller: &Caller<'_>) -> Reply {
+    if !matches!(caller, Caller::Admin) {
+        return Err(fail(StatusCode::FORBIDDEN, "only the admin token can reload every city"));
+    }
+    match lists::load_cities("admin endpoint").await {
+        Ok(changes) => Ok(reply(StatusCode::OK, changes)),
+        Err(_) => Err(fail(StatusCode::INTERNAL_SERVER_ERROR, "reload failed, previous cities kept")),
+    }
+}
+
+fn list(caller: &Caller<'_>) -> Response<Body> {
+    let cities = CITIES.load();
+    let mut views: Vec<CityView> = cities.cities.values().filter(|city| caller.manages(city)).map(From::from).collect();
+    views.sort_unstable_by_key(|view| view.id);
+    reply(StatusCode::OK, views)
+}
+
+fn show(caller: &Caller<'_>, id: &str) -> Reply {
+    let id = city_id(caller, id)?;
+    let cities = CITIES.load();
+    let city = cities.cities.get(&id).ok_or_else(|| fail(StatusCode::NOT_FOUND, "unknown city"))?;
+    Ok(reply(StatusCode::OK, CityView::from(city)))
+}
+
+async fn stats(caller: &Caller<'_>, id: &str, req: &Request<Body>) -> Reply {
+    let id = city_id(caller, id)?;
+    // `?day=YYYY-MM-DD`, defaults to today in the city timezone
+    let day =
+        match req.uri().query().into_iter().flat_map(|query| query.split('&')).find_map(|p| p.strip_prefix("day=")) {
+            Some(day) => NaiveDate::parse_from_str(day, "%Y-%m-%d")
+                .map_err(|_| fail(StatusCode::BAD_REQUEST, "day must be formatted as YYYY-MM-DD"))?,
+            None => stats::day(Some(id), Utc::now()),
+        };
+    let stats = reader().city_stats(id, day).await.map_err(|e| {
+        error!("admin city {} stats error: {}", id, e);
+        fail(StatusCode::INTERNAL_SERVER_ERROR, "stats unavailable")
+    })?;
+    Ok(reply(StatusCode::OK, stats))
+}
+
+// reloads the cities to apply a change, replying with the updated city
+async fn saved(caller: &Caller<'_>, id: u16, updated: bool, what: &str) -> Reply {
+    if !updated {
+        return Err(fail(StatusCode::CONFLICT, "the city isn't stored in the database, edit its file instead"));
+    }
+    info!("city {} {} changed by {}", id, what, caller);
+    // a replica could still miss the change
+    lists::load_cities_from(storage(), "admin api")
+        .await
+        .map_err(|_| fail(StatusCode::INTERNAL_SERVER_ERROR, "saved, but the cities reload failed"))?;
+    show(caller, &id.to_string())
+}
+
+async fn coordinates(caller: &Caller<'_>, id: &str, req: Request<Body>) -> Reply {
+    let id = city_id(caller, id)?;
+    // WKT, GeoJSON or the legacy format, like the column
+    let body = read_body(req).await?;
+    let geometry = geometry::parse(&body).map_err(|e| fail(StatusCode::BAD_REQUEST, e))?;
+    let updated =
+        storage().update_city_coordinates(id, body.trim(), &geometry::to_wkt(&geometry)).await.map_err(|e| {
+            error!("admin city {} coordinates error: {}", id, e);
+            fail(StatusCode::INTERNAL_SERVER_ERROR, "update failed")
+        })?;
+    saved(caller, id, updated, "coordinates").await
+}
+
+async fn monitor(caller: &Caller<'_>, id: &str, req: Request<Body>) -> Reply {
+    let id = city_id(caller, id)?;
+    // `true`/`false`, or the raw column value
+    let monitor = match serde_json::from_str(&read_body(req).await?) {
+        Ok(Value::Bool(enabled)) => u8::from(enabled),
+        Ok(Value::Number(n)) => n
+            .as_u64()
+            .and_then(|n| u8::try_from(n).ok())
+            .ok_or_else(|| fail(StatusCode::BAD_REQUEST, "monitor must be between 0 and 255"))?,
+        _ => return Err(fail(StatusCode::BAD_REQUEST, "expected true, false or a number")),
+    };
+    let updated = storage().update_city_monitor(id, monitor).await.map_err(|e| {
+        error!("admin city {} monitor error: {}", id, e);
+        fail(StatusCode::INTERNAL_SERVER_ERROR, "update failed")
+    })?;
+    saved(caller, id, updated, "monitor").await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens() {
+        assert!(same_token("s3cret", "s3cret"));
+        assert!(!same_token("s3cret", "s3cre"));
+        assert!(!same_token("s3cret", "S3cret"));
+        assert!(!same_token("s3cret", ""));
+    }
+}
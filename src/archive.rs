@@ -0,0 +1,191 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Mutex as SyncMutex,
+};
+
+use chrono::NaiveDate;
+
+use rocketmap_entities::Pokemon;
+
+use serde_json::{json, Value};
+
+use tokio::{
+    fs::{File, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+
+use once_cell::sync::Lazy;
+
+use tracing::error;
+
+use crate::{
+    config::{MergePolicy::Always, CONFIG},
+    db::storage,
+    pvp::Leagues,
+    upsert::Upsert,
+};
+
+// daily partitions already created during this run
+static TABLES: Lazy<SyncMutex<HashSet<String>>> = Lazy::new(Default::default);
+
+// currently open archive file, rotated when the day changes
+static FILE: Lazy<Mutex<Option<ArchiveFile>>> = Lazy::new(Default::default);
+
+// encounter fields worth a new line when they change, the others change at every sighting
+const DETAILS: &[&str] = &[
+    "pokemon_id",
+    "form",
+    "costume",
+    "gender",
+    "cp",
+    "individual_attack",
+    "individual_defense",
+    "individual_stamina",
+    "move_1",
+    "move_2",
+    "weight",
+    "height",
+    "pokemon_level",
+    "shiny",
+    "display_pokemon_id",
+    "pvp",
+];
+
+struct ArchiveFile {
+    day: NaiveDate,
+    file: File,
+    // hashed details of the encounters already written, by hashed encounter id
+    written: HashMap<u64, u64>,
+}
+
+fn hash(value: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn details(data: &Value) -> u64 {
+    hash(DETAILS.iter().map(|field| data[*field].to_string()).collect::<Vec<_>>())
+}
+
+/// Appends the full encounter to the configured archives of `day`, if any, the stats day it was seen in
+pub async fn archive_pokemon(pokemon: &Pokemon, pvp: &Leagues, day: NaiveDate) {
+    let Some(config) = CONFIG.archive.as_ref() else {
+        return;
+    };
+
+    let data = encounter_to_json(pokemon, pvp);
+
+    if let Some(table) = config.table.as_deref() {
+        archive_to_table(table, day, pokemon, &data).await.ok();
+    }
+
+    if let Some(path) = config.path.as_deref() {
+        archive_to_file(path, day, &pokemon.encounter_id, &data).await.ok();
+    }
+}
+
+fn encounter_to_json(pokemon: &Pokemon, pvp: &Leagues) -> Value {
+    json!({
+        "encounter_id": pokemon.encounter_id,
+        "pokemon_id": pokemon.pokemon_id,
+        "form": pokemon.form,
+        "costume": pokemon.costume,
+        "gender": pokemon.gender.get_id(),
+        "spawnpoint_id": pokemon.spawnpoint_id,
+        "pokestop_id": pokemon.pokestop_id,
+        "latitude": pokemon.latitude,
+        "longitude": pokemon.longitude,
+        "disappear_time": pokemon.disappear_time,
+        "disappear_time_verified": pokemon.disappear_time_verified,
+        "first_seen": pokemon.first_seen,
+        "last_modified_time": pokemon.last_modified_time,
+        "cp": pokemon.cp,
+        "individual_attack": pokemon.individual_attack,
+        "individual_defense": pokemon.individual_defense,
+        "individual_stamina": pokemon.individual_stamina,
+        "move_1": pokemon.move_1,
+        "move_2": pokemon.move_2,
+        "weight": pokemon.weight,
+        "height": pokemon.height,
+        "capture_1": pokemon.capture_1,
+        "capture_2": pokemon.capture_2,
+        "capture_3": pokemon.capture_3,
+        "weather": pokemon.weather,
+        "pokemon_level": pokemon.pokemon_level,
+        "s2_cell_id": pokemon.s2_cell_id,
+        "username": pokemon.username,
+        "shiny": pokemon.shiny,
+        "display_pokemon_id": pokemon.display_pokemon_id,
+        "is_event": pokemon.is_event,
+        "pvp": pvp,
+    })
+}
+
+async fn archive_to_table(table: &str, day: NaiveDate, pokemon: &Pokemon, data: &Value) -> Result<(), ()> {
+    let partition = format!("{}_{}", table, day.format("%Y%m%d"));
+
+    // the base table acts as a template for the daily partitions
+    let created = TABLES.lock().map(|tables| tables.contains(&partition)).unwrap_or_default();
+    if !created {
+        storage()
+            .create_partition(table, &partition)
+            .await
+            .map_err(|e| error!("query error: create archive table {}\n{}", partition, e))?;
+        if let Ok(mut tables) = TABLES.lock() {
+            tables.insert(partition.clone());
+        }
+    }
+
+    storage()
+        .upsert(
+            Upsert::new(partition)
+                .key("id", pokemon.encounter_id.as_str())
+                .column("pokemon_id", pokemon.pokemon_id, Always)
+                .column("form", pokemon.form, Always)
+                .column("lat", pokemon.latitude, Always)
+                .column("lon", pokemon.longitude, Always)
+                .column("expire_timestamp", pokemon.disappear_time, Always)
+                .column("atk_iv", pokemon.individual_attack, Always)
+                .column("def_iv", pokemon.individual_defense, Always)
+                .column("sta_iv", pokemon.individual_stamina, Always)
+                .column("cp", pokemon.cp, Always)
+                .column("level", pokemon.pokemon_level, Always)
+                .column("shiny", pokemon.shiny, Always)
+                .column("data", data.to_string(), Always),
+        )
+        .await
+        .map_err(|e| error!("query error: insert archive pokemon\n{}", e))?;
+    Ok(())
+}
+
+// like the table, the file keeps encounters instead of sightings: a line is only added when the details change
+async fn archive_to_file(path: &str, day: NaiveDate, encounter_id: &str, data: &Value) -> Result<(), ()> {
+    let (encounter, details) = (hash(encounter_id), details(data));
+    let mut line = data.to_string();
+    line.push('\n');
+
+    // holding the lock for the whole write keeps lines from interleaving
+    let mut lock = FILE.lock().await;
+    if lock.as_ref().is_none_or(|archive| archive.day != day) {
+        let file_name = format!("{}/pokemon-{}.jsonl", path.trim_end_matches('/'), day.format("%Y-%m-%d"));
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&file_name)
+            .await
+            .map_err(|e| error!("Archive file {} open error: {}", file_name, e))?;
+        *lock = Some(ArchiveFile { day, file, written: HashMap::new() });
+    }
+
+    if let Some(archive) = lock.as_mut() {
+        if archive.written.get(&encounter) == Some(&details) {
+            return Ok(());
+        }
+        archive.file.write_all(line.as_bytes()).await.map_err(|e| error!("Archive file write error: {}", e))?;
+        archive.written.insert(encounter, details);
+    }
+    Ok(())
+}
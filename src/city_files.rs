@@ -0,0 +1,107 @@
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde_json::Value;
+
+use tokio::fs;
+
+use crate::{
+    geometry,
+    lists::{self, City},
+};
+
+/// Modification times of the watched files, a change in any of them means a reload
+pub type Fingerprint = Vec<(PathBuf, Option<SystemTime>, u64)>;
+
+// a directory is read as a whole, hidden and non GeoJSON files are skipped
+async fn files(path: &Path) -> Result<Vec<PathBuf>, String> {
+    let metadata = fs::metadata(path).await.map_err(|e| format!("{}: {}", path.display(), e))?;
+    if !metadata.is_dir() {
+        return Ok(vec![path.to_owned()]);
+    }
+
+    let mut entries = fs::read_dir(path).await.map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut files = Vec::new();
+    while let Some(entry) = entries.next_entry().await.map_err(|e| format!("{}: {}", path.display(), e))? {
+        let file = entry.path();
+        let hidden = file.file_name().and_then(|name| name.to_str()).is_none_or(|name| name.starts_with('.'));
+        let geojson =
+            file.extension().and_then(|ext| ext.to_str()).is_some_and(|ext| ext == "geojson" || ext == "json");
+        if !hidden && geojson {
+            files.push(file);
+        }
+    }
+    // stable order, so that duplicated ids always resolve the same way
+    files.sort();
+    Ok(files)
+}
+
+pub async fn fingerprint(path: &Path) -> Result<Fingerprint, String> {
+    let mut fingerprint = Vec::new();
+    for file in files(path).await? {
+        let metadata = fs::metadata(&file).await.map_err(|e| format!("{}: {}", file.display(), e))?;
+        fingerprint.push((file, metadata.modified().ok(), metadata.len()));
+    }
+    Ok(fingerprint)
+}
+
+/// Reads every city from a GeoJSON file, or from all the GeoJSON files of a directory
+///
+/// Unreadable files fail the whole load, a malformed feature is reported on its own.
+pub async fn load(path: &Path) -> Result<Vec<Result<City, String>>, String> {
+    let mut cities = Vec::new();
+    for file in files(path).await? {
+        let content = fs::read_to_string(&file).await.map_err(|e| format!("{}: {}", file.display(), e))?;
+        let value: Value =
+            serde_json::from_str(&content).map_err(|e| format!("{}: invalid GeoJSON: {}", file.display(), e))?;
+        let features = match value.get("type").and_then(Value::as_str) {
+            Some("FeatureCollection") => value
+                .get("features")
+                .and_then(Value::as_array)
+                .ok_or_else(|| format!("{}: collection without features", file.display()))?
+                .iter()
+                .collect(),
+            Some("Feature") => vec![&value],
+            other => {
+                return Err(format!("{}: expected a Feature or a FeatureCollection, found {:?}", file.display(), other))
+            }
+        };
+        for (index, feature) in features.into_iter().enumerate() {
+            cities.push(city(feature).map_err(|e| format!("{} feature #{}: {}", file.display(), index + 1, e)));
+        }
+    }
+    Ok(cities)
+}
+
+// properties mirror the `city` table columns, only `id` and `name` are mandatory
+fn city(feature: &Value) -> Result<City, String> {
+    let properties = feature.get("properties").ok_or("feature without properties")?;
+    let id = properties
+        .get("id")
+        .and_then(Value::as_u64)
+        .and_then(|id| u16::try_from(id).ok())
+        .ok_or("feature without a valid \"id\" property")?;
+    let name = properties.get("name").and_then(Value::as_str).ok_or_else(|| format!("city {} without \"name\"", id))?;
+    let coordinates = geometry::from_geojson(feature.get("geometry").ok_or("feature without geometry")?)
+        .map_err(|e| format!("city \"{}\" ({}) geometry: {}", name, id, e))?;
+    let admins_users = match properties.get("admins_users") {
+        Some(Value::String(s)) => s.split_whitespace().map(|s| s.to_owned()).collect(),
+        Some(Value::Array(users)) => users.iter().filter_map(Value::as_str).map(|s| s.to_owned()).collect(),
+        _ => Vec::new(),
+    };
+    let timezone = lists::parse_timezone(properties.get("timezone").and_then(Value::as_str))
+        .map_err(|e| format!("city \"{}\" ({}) {}", name, id, e))?;
+
+    Ok(City {
+        id,
+        name: name.to_owned(),
+        coordinates,
+        // file cities never expire unless told otherwise
+        scadenza: properties.get("scadenza").and_then(Value::as_i64).unwrap_or(i64::MAX),
+        scan_iv: properties.get("monitor").and_then(Value::as_u64).and_then(|m| u8::try_from(m).ok()).unwrap_or(1),
+        admins_users,
+        timezone,
+    })
+}
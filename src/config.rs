@@ -1,9 +1,12 @@
+use std::collections::HashMap;
 use std::env;
 use std::fs::File;
 use std::io::Read;
 #[cfg(test)]
 use std::path::PathBuf;
 
+use chrono_tz::Tz;
+
 use serde::Deserialize;
 
 use once_cell::sync::Lazy;
@@ -14,6 +17,14 @@ pub static CONFIG: Lazy<Config> = Lazy::new(Config::new);
 pub struct Config {
     pub service: Service,
     pub database: Database,
+    pub archive: Option<Archive>,
+    #[serde(default)]
+    pub merge: HashMap<String, Merge>,
+    pub retention: Option<Retention>,
+    pub geofence: Option<Geofence>,
+    pub city_files: Option<CityFiles>,
+    pub admin: Option<Admin>,
+    pub stats: Option<Stats>,
 }
 
 #[derive(Deserialize)]
@@ -21,16 +32,217 @@ pub struct Service {
     pub address: Option<String>,
     pub port: Option<u32>,
     pub safeword: Option<String>,
+    /// seconds between two city reloads, defaults to 1800, 0 disables the periodic reload
+    pub cities_reload: Option<u64>,
+    /// start even when the cities or the database can't be reached, retrying every minute
+    ///
+    /// The database schema is then checked once it answers, a mismatch stops the service at that point.
+    #[serde(default)]
+    pub cities_optional: bool,
+}
+
+/// Administration endpoints under `/admin/`, disabled unless configured
+///
+/// Tokens are expected as `Authorization: Bearer <token>`.
+#[derive(Deserialize)]
+pub struct Admin {
+    /// token allowed to manage every city
+    pub token: Option<String>,
+    /// tokens of the city admins, mapped to the usernames listed in `city.admins_users`
+    #[serde(default)]
+    pub users: HashMap<String, String>,
 }
 
 #[derive(Deserialize)]
 pub struct Database {
     pub url: String,
+    /// apply pending migrations on startup instead of refusing to start, defaults to true only for SQLite
+    pub auto_migrate: Option<bool>,
+    /// schema the database follows, `merge` overrides keep using rdm names anyway
+    #[serde(default)]
+    pub profile: Profile,
+    /// prepared statements kept per connection, defaults to 128
+    pub statement_cache: Option<usize>,
+    /// replica used for reads, defaults to `url`
+    pub read_url: Option<String>,
+    /// databases receiving a best-effort copy of every write, failed writes are logged and counted but not retried
+    #[serde(default)]
+    pub mirrors: Vec<Mirror>,
+    /// connection pool tuning, unset values keep the url (or driver) defaults
+    #[serde(default)]
+    pub pool: Pool,
+}
+
+#[derive(Clone, Default, Deserialize)]
+pub struct Pool {
+    /// connections kept open even when idle (MySQL only)
+    pub min_connections: Option<usize>,
+    pub max_connections: Option<usize>,
+    /// seconds to wait for a free connection before failing the query
+    pub acquire_timeout: Option<u64>,
+    /// seconds after which an idle connection is closed
+    pub idle_ttl: Option<u64>,
+    /// seconds a statement can run (on MySQL only SELECTs can be limited)
+    pub statement_timeout: Option<u64>,
+    /// seconds of inactivity before TCP keepalive probes are sent
+    pub tcp_keepalive: Option<u64>,
+    /// milliseconds after which waiting for a connection is logged, defaults to 500
+    pub slow_acquire: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct Mirror {
+    pub url: String,
+    #[serde(default)]
+    pub profile: Profile,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Profile {
+    /// RealDeviceMap, the schema hookedmap was born with
+    #[default]
+    Rdm,
+    /// Golbat, RDM derived with a few renames
+    Golbat,
+    /// classic RocketMap, only the columns of the main tables are written
+    Rocketmap,
+}
+
+/// Archives of the pokemon encounters, split by the day they were seen in, like the stats
+#[derive(Deserialize)]
+pub struct Archive {
+    /// base table, daily partitions are created as `<table>_YYYYMMDD` using it as template
+    pub table: Option<String>,
+    /// directory where daily `pokemon-YYYY-MM-DD.jsonl` files are written
+    ///
+    /// An encounter is written again only when its IVs, moves, form or PvP rankings change.
+    pub path: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Retention {
+    /// seconds between two cleanups, defaults to 300
+    pub interval: Option<u64>,
+    /// seconds after their expiry before pokemon are removed, unset keeps them forever
+    pub pokemon: Option<u64>,
+    /// move expired pokemon into `pokemon_expired` instead of deleting them
+    #[serde(default)]
+    pub keep_expired_pokemon: bool,
+    /// clear expired lures, incidents and quests, defaults to true
+    pub clear_expired: Option<bool>,
+    /// days without updates after which gyms and pokestops are removed, unset keeps them forever
+    pub stale_days: Option<u64>,
+    /// rows touched by a single statement, defaults to 1000
+    pub batch_size: Option<u64>,
+    /// milliseconds to wait between two statements, defaults to 100
+    pub batch_pause: Option<u64>,
+}
+
+/// Filter applied to incoming entities before any database work
+#[derive(Deserialize)]
+pub struct Geofence {
+    #[serde(default)]
+    pub action: GeofenceAction,
+    /// entities must fall inside a city polygon (or an `include` area)
+    #[serde(default)]
+    pub cities: bool,
+    /// areas accepted even outside of every city
+    #[serde(default)]
+    pub include: Vec<Area>,
+    /// areas always rejected
+    #[serde(default)]
+    pub exclude: Vec<Area>,
+    /// entities inside cities whose subscription (`scadenza`) is over are rejected too
+    #[serde(default)]
+    pub expired_cities: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GeofenceAction {
+    /// entities outside the geofence are discarded
+    #[default]
+    Drop,
+    /// entities outside the geofence are written anyway, only logged at debug level and counted, to try a geofence out
+    ///
+    /// Nothing marks them in the database. `tag` is still accepted as its old name.
+    #[serde(alias = "tag")]
+    Log,
+}
+
+#[derive(Deserialize)]
+pub struct Area {
+    pub name: String,
+    /// `[lat, lon]` pairs, like city coordinates
+    pub points: Vec<[f64; 2]>,
+}
+
+/// Cities read from GeoJSON files, e.g. for test setups or geofences kept under version control
+#[derive(Deserialize)]
+pub struct CityFiles {
+    /// a FeatureCollection file, or a directory whose `.geojson` and `.json` files are all read
+    pub path: String,
+    #[serde(default)]
+    pub source: CitySource,
+    /// seconds between two checks for changed files, defaults to 10, 0 disables the watch
+    pub watch: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CitySource {
+    /// files are added to the `city` table, replacing database cities with the same id
+    #[default]
+    Merge,
+    /// files only, the `city` table isn't read
+    Files,
+}
+
+#[derive(Deserialize)]
+pub struct Stats {
+    /// seconds between two writes of the counters aggregated in memory, defaults to 60
+    pub flush_interval: Option<u64>,
+    /// IANA timezone splitting stats into days, like "Europe/Rome", defaults to UTC, cities can override it
+    ///
+    /// Quests expire at its midnight too, whatever the city.
+    pub timezone: Option<Tz>,
+    /// seconds between two compactions of daily stats into weekly and monthly ones, defaults to 3600, 0 disables them
+    pub compaction_interval: Option<u64>,
+    /// days hourly stats are kept for, forever when missing
+    pub hourly_days: Option<u64>,
+    /// days daily stats are kept for once compacted, forever when missing
+    pub daily_days: Option<u64>,
+    /// days per city counters are kept for, forever when missing
+    pub city_days: Option<u64>,
+}
+
+/// Per table overrides of the merge policies used on upserts
+#[derive(Deserialize)]
+pub struct Merge {
+    /// column compared by `if_newer` policies
+    pub version: Option<String>,
+    #[serde(default)]
+    pub columns: HashMap<String, MergePolicy>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MergePolicy {
+    /// always overwrite with the incoming value
+    Always,
+    /// overwrite only when the incoming value isn't null
+    IfNotNull,
+    /// overwrite only when the incoming version isn't older than the stored one
+    IfNewer,
+    /// write only on insert
+    Never,
 }
 
 impl Config {
     fn new() -> Self {
-        let args: Vec<String> = env::args().collect();
+        // subcommands aren't config paths
+        let args: Vec<String> = env::args().filter(|arg| arg != "migrate").collect();
 
         //config file can be the first argument
         let config_file = if args.len() > 1 {
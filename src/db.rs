@@ -1,13 +1,19 @@
-use mysql_async::{Conn, Pool};
-
 use once_cell::sync::Lazy;
 
-use tracing::error;
+use crate::{
+    config::CONFIG,
+    storage::{self, Storage},
+};
+
+static STORAGE: Lazy<Box<dyn Storage>> = Lazy::new(|| storage::connect(&CONFIG.database));
 
-use crate::config::CONFIG;
+static READER: Lazy<Option<Box<dyn Storage>>> = Lazy::new(|| storage::connect_reader(&CONFIG.database));
 
-static MYSQL: Lazy<Pool> = Lazy::new(|| Pool::new(CONFIG.database.url.as_str()));
+pub fn storage() -> &'static dyn Storage {
+    STORAGE.as_ref()
+}
 
-pub async fn get_conn() -> Result<Conn, ()> {
-    MYSQL.get_conn().await.map_err(|e| error!("MySQL connection error: {}", e))
+/// Storage used for reads, the main one unless a replica is configured
+pub fn reader() -> &'static dyn Storage {
+    READER.as_deref().unwrap_or_else(storage)
 }
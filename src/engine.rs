@@ -1,19 +1,34 @@
-use geo::Point;
+use std::future::Future;
 
-use geo_raycasting::RayCasting;
+use geo::Point;
 
 use rocketmap_entities::{Gym, GymDetails, Pokemon, Pokestop, Quest, Raid};
 
-use mysql_async::{params, prelude::Queryable};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+
+use once_cell::sync::Lazy;
 
-use chrono::{DateTime, TimeZone, Utc};
+use tokio_util::task::TaskTracker;
 
 use tracing::error;
 
-use crate::{db::get_conn, lists::CITIES};
+use crate::{
+    archive::archive_pokemon,
+    config::MergePolicy::{self, Always, IfNotNull, Never},
+    db::storage,
+    geofence,
+    lists::{find_active_city, scan_iv},
+    pvp::Leagues,
+    stats,
+    storage::EntityStat,
+    upsert::Upsert,
+};
 
 type Request = rocketmap_entities::Request<FakeCache, FakeCache>;
 
+// writes still running, waited for on shutdown
+static TASKS: Lazy<TaskTracker> = Lazy::new(TaskTracker::new);
+
 #[derive(Debug)]
 pub struct FakeCache;
 
@@ -28,236 +43,428 @@ impl rocketmap_entities::gamemaster::Cache for FakeCache {
 }
 
 async fn update_gym(gym: &Gym) -> Result<(), ()> {
-    let mut conn = get_conn().await?;
-    conn.exec_drop(
-        format!(
-            "INSERT INTO gym (id, updated, first_seen_timestamp, lat, lon, name, url, last_modified_timestamp, enabled, team_id, guarding_pokemon_id, availble_slots, raid_end_timestamp, ex_raid_eligible, in_battle, sponsor_id, ar_scan_eligible)
-            VALUES (:id, UNIX_TIMESTAMP(), UNIX_TIMESTAMP(), :lat, :lon, :name, :url, :timestamp, :enabled, :team_id, :guard_id, :slots, :raid_end, :ex, :in_battle, :sponsor, :ar)
-            ON DUPLICATE KEY UPDATE updated = UNIX_TIMESTAMP(), lat = :lat, lon = :lon,{}{}{}{} team_id = :team_id,{} availble_slots = :slots,{}{} in_battle = :in_battle, sponsor_id = :sponsor, ar_scan_eligible = :ar;",
-            (!gym.gym_name.eq_ignore_ascii_case("unknown")).then_some(" name = :name,").unwrap_or_default(),
-            (!gym.url.is_empty()).then_some(" url = :url,").unwrap_or_default(),
-            gym.last_modified.map(|_| " last_modified_timestamp = :timestamp,").unwrap_or_default(),
-            gym.enabled.map(|_| " enabled = :enabled,").unwrap_or_default(),
-            gym.guard_pokemon_id.map(|_| " guarding_pokemon_id = :guard_id,").unwrap_or_default(),
-            gym.raid_active_until.map(|_| " raid_end_timestamp = :raid_end,").unwrap_or_default(),
-            gym.ex_raid_eligible.map(|_| " ex_raid_eligible = :ex,").unwrap_or_default(),
-        ),
-        params! {
-            "id" => gym.gym_id.as_str(),
-            "lat" => gym.latitude,
-            "lon" => gym.longitude,
-            "name" => gym.gym_name.as_str(),
-            "url" => gym.url.as_str(),
-            "timestamp" => gym.last_modified,
-            "enabled" => gym.enabled,
-            "team_id" => gym.team_id.get_id(),
-            "guard_id" => gym.guard_pokemon_id,
-            "slots" => gym.slots_available,
-            "raid_end" => gym.raid_active_until,
-            "ex" => gym.ex_raid_eligible,
-            "in_battle" => gym.in_battle,
-            "sponsor" => gym.sponsor_id,
-            "ar" => gym.ar_scan_eligible,
-        })
+    let now = Utc::now().timestamp();
+    storage()
+        .upsert(
+            Upsert::new("gym")
+                .key("id", gym.gym_id.as_str())
+                .column("updated", now, Always)
+                .column("first_seen_timestamp", now, Never)
+                .column("lat", gym.latitude, Always)
+                .column("lon", gym.longitude, Always)
+                .column("name", known(&gym.gym_name), IfNotNull)
+                .column("url", known(&gym.url), IfNotNull)
+                .column("last_modified_timestamp", gym.last_modified, IfNotNull)
+                .column("enabled", gym.enabled, IfNotNull)
+                .column("team_id", gym.team_id.get_id(), Always)
+                .column("guarding_pokemon_id", gym.guard_pokemon_id, IfNotNull)
+                .column("availble_slots", gym.slots_available, Always)
+                .column("raid_end_timestamp", gym.raid_active_until, IfNotNull)
+                .column("ex_raid_eligible", gym.ex_raid_eligible, IfNotNull)
+                .column("in_battle", gym.in_battle, Always)
+                .column("sponsor_id", gym.sponsor_id, Always)
+                .column("ar_scan_eligible", gym.ar_scan_eligible, Always),
+        )
         .await
-        .map_err(|e| error!("Mysql update gym error: {}\n{:?}", e, gym))?;
+        .map_err(|e| error!("update gym error: {}\n{:?}", e, gym))?;
+
+    update_gym_team_stats((gym.latitude, gym.longitude).into(), &gym.gym_id, gym.team_id.get_id());
+
     Ok(())
 }
 
 async fn update_gym_details(gym: &GymDetails) -> Result<(), ()> {
-    let mut conn = get_conn().await?;
-    conn.exec_drop(
-        format!(
-            "INSERT INTO gym (id, updated, first_seen_timestamp, lat, lon, name, url, team_id, guarding_pokemon_id, availble_slots, ex_raid_eligible, in_battle, sponsor_id, ar_scan_eligible)
-            VALUES (:id, UNIX_TIMESTAMP(), UNIX_TIMESTAMP(), :lat, :lon, :name, :url, :team_id, :guard_id, :slots, :ex, :in_battle, :sponsor, :ar)
-            ON DUPLICATE KEY UPDATE updated = UNIX_TIMESTAMP(), lat = :lat, lon = :lon,{}{} team_id = :team_id,{} availble_slots = :slots, ex_raid_eligible = :ex, in_battle = :in_battle, sponsor_id = :sponsor, ar_scan_eligible = :ar;",
-            (!gym.name.eq_ignore_ascii_case("unknown")).then_some(" name = :name,").unwrap_or_default(),
-            (!gym.url.is_empty()).then_some(" url = :url,").unwrap_or_default(),
-            gym.guard_pokemon_id.map(|_| " guarding_pokemon_id = :guard_id,").unwrap_or_default(),
-        ),
-        params! {
-            "id" => gym.id.as_str(),
-            "lat" => gym.latitude,
-            "lon" => gym.longitude,
-            "name" => gym.name.as_str(),
-            "url" => gym.url.as_str(),
-            "team_id" => gym.team.get_id(),
-            "guard_id" => gym.guard_pokemon_id,
-            "slots" => gym.slots_available,
-            "ex" => gym.ex_raid_eligible,
-            "in_battle" => gym.in_battle,
-            "sponsor" => gym.sponsor_id,
-            "ar" => gym.ar_scan_eligible,
-        })
+    let now = Utc::now().timestamp();
+    storage()
+        .upsert(
+            Upsert::new("gym")
+                .key("id", gym.id.as_str())
+                .column("updated", now, Always)
+                .column("first_seen_timestamp", now, Never)
+                .column("lat", gym.latitude, Always)
+                .column("lon", gym.longitude, Always)
+                .column("name", known(&gym.name), IfNotNull)
+                .column("url", known(&gym.url), IfNotNull)
+                .column("team_id", gym.team.get_id(), Always)
+                .column("guarding_pokemon_id", gym.guard_pokemon_id, IfNotNull)
+                .column("availble_slots", gym.slots_available, Always)
+                .column("ex_raid_eligible", gym.ex_raid_eligible, Always)
+                .column("in_battle", gym.in_battle, Always)
+                .column("sponsor_id", gym.sponsor_id, Always)
+                .column("ar_scan_eligible", gym.ar_scan_eligible, Always),
+        )
         .await
-        .map_err(|e| error!("Mysql update gym_details error: {}\n{:?}", e, gym))?;
+        .map_err(|e| error!("update gym_details error: {}\n{:?}", e, gym))?;
+
+    update_gym_team_stats((gym.latitude, gym.longitude).into(), &gym.id, gym.team.get_id());
+
     Ok(())
 }
 
 async fn update_pokestop(pokestop: &Pokestop) -> Result<(), ()> {
-    let mut conn = get_conn().await?;
-    conn.exec_drop(
-        format!(
-            "INSERT INTO pokestop (id, first_seen_timestamp, lat, lon, name, url, enabled, last_modified_timestamp, lure_expire_timestamp, pokestop_display, incident_expire_timestamp, updated, lure_id, grunt_type, ar_scan_eligible)
-            VALUES (:id, UNIX_TIMESTAMP(), :lat, :lon, :name, :url, :enabled, :last, :lure_exp, :display, :incident_exp, :updated, :lure_id, :grunt_type, :ar)
-            ON DUPLICATE KEY UPDATE lat = :lat, lon = :lon,{}{}{} last_modified_timestamp = :last, lure_expire_timestamp = :lure_exp,{} incident_expire_timestamp = :incident_exp, updated = :updated, lure_id = :lure_id, grunt_type = :grunt_type, ar_scan_eligible = :ar;",
-            pokestop.name.as_ref().map(|_| " name = :name,").unwrap_or_default(),
-            pokestop.url.as_ref().map(|_| " url = :url,").unwrap_or_default(),
-            pokestop.enabled.map(|_| " enabled = :enabled,").unwrap_or_default(),
-            pokestop.pokestop_display.map(|_| " pokestop_display = :display,").unwrap_or_default(),
-        ),
-        params! {
-            "id" => pokestop.pokestop_id.as_str(),
-            "lat" => pokestop.latitude,
-            "lon" => pokestop.longitude,
-            "name" => pokestop.name.as_deref(),
-            "url" => pokestop.url.as_deref(),
-            "enabled" => pokestop.enabled,
-            "last" => pokestop.last_modified,
-            "lure_exp" => pokestop.lure_expiration,
-            "display" => pokestop.pokestop_display,
-            "incident_exp" => pokestop.incident_expire_timestamp,
-            "updated" => pokestop.updated,
-            "lure_id" => pokestop.lure_id,
-            "grunt_type" => pokestop.get_grunt_type(),
-            "ar" => pokestop.ar_scan_eligible,
-        })
+    storage()
+        .upsert(
+            Upsert::new("pokestop")
+                .version("updated")
+                .key("id", pokestop.pokestop_id.as_str())
+                .column("first_seen_timestamp", Utc::now().timestamp(), Never)
+                .column("lat", pokestop.latitude, Always)
+                .column("lon", pokestop.longitude, Always)
+                .column("name", pokestop.name.as_deref(), IfNotNull)
+                .column("url", pokestop.url.as_deref(), IfNotNull)
+                .column("enabled", pokestop.enabled, IfNotNull)
+                .column("last_modified_timestamp", pokestop.last_modified, Always)
+                .column("lure_expire_timestamp", pokestop.lure_expiration, Always)
+                .column("pokestop_display", pokestop.pokestop_display, IfNotNull)
+                .column("incident_expire_timestamp", pokestop.incident_expire_timestamp, Always)
+                .column("updated", pokestop.updated, Always)
+                .column("lure_id", pokestop.lure_id, Always)
+                .column("grunt_type", pokestop.get_grunt_type(), Always)
+                .column("ar_scan_eligible", pokestop.ar_scan_eligible, Always),
+        )
         .await
-        .map_err(|e| error!("Mysql update pokestop error: {}\n{:?}", e, pokestop))?;
+        .map_err(|e| error!("update pokestop error: {}\n{:?}", e, pokestop))?;
+
+    update_pokestop_events(pokestop).await.ok();
+
+    Ok(())
+}
+
+async fn update_pokestop_events(pokestop: &Pokestop) -> Result<(), ()> {
+    let now = Utc::now().timestamp();
+    let lure = pokestop.lure_id.zip(pokestop.lure_expiration).filter(|(id, expire)| *id > 0 && *expire > now);
+    let display = pokestop
+        .pokestop_display
+        .zip(pokestop.incident_expire_timestamp)
+        .filter(|(id, expire)| *id > 0 && *expire > now);
+    if lure.is_none() && display.is_none() {
+        return Ok(());
+    }
+
+    // an event is identified by its expiry, the first time we see it is its start
+    if let Some((lure_id, expire)) = lure {
+        let inserted = storage()
+            .upsert(
+                Upsert::new("pokestop_event")
+                    .key("pokestop_id", pokestop.pokestop_id.as_str())
+                    .key("event", "lure")
+                    .key("expire", expire)
+                    .column("type_id", lure_id, Never)
+                    .column("start", now, Never),
+            )
+            .await
+            .map_err(|e| error!("update pokestop lure event error: {}\n{:?}", e, pokestop))?;
+
+        if inserted > 0 {
+            update_city_lure_stats((pokestop.latitude, pokestop.longitude).into(), lure_id, now);
+        }
+    }
+
+    if let Some((display, expire)) = display {
+        storage()
+            .upsert(
+                Upsert::new("pokestop_event")
+                    .key("pokestop_id", pokestop.pokestop_id.as_str())
+                    .key("event", "display")
+                    .key("expire", expire)
+                    .column("type_id", display, Never)
+                    .column("start", now, Never),
+            )
+            .await
+            .map_err(|e| error!("update pokestop display event error: {}\n{:?}", e, pokestop))?;
+    }
+
     Ok(())
 }
 
-async fn update_pokemon(pokemon: &Pokemon) -> Result<(), ()> {
-    let mut conn = get_conn().await?;
-    conn.exec_drop(
-        format!(
-            "INSERT INTO pokemon (id, pokemon_id, pokestop_id, lat, lon, expire_timestamp, expire_timestamp_verified, updated, first_seen_timestamp, gender, cp, form, costume, atk_iv, def_iv, sta_iv, move_1, move_2, weight, size, capture_1, capture_2, capture_3, weather, level, cell_id, username, shiny, display_pokemon_id, is_event, pvp_rankings_great_league, pvp_rankings_ultra_league)
-            VALUES (:id, :pokemon, :pokestop, :lat, :lon, :exp, :exp_ver, :update, :first_seen, :gender, :cp, :form, :costume, :atk_iv, :def_iv, :sta_iv, :move1, :move2, :weight, :size, :capture1, :capture2, :capture3, :weather, :level, :cell, :username, :shiny, :display, :event, :great, :ultra)
-            ON DUPLICATE KEY UPDATE pokemon_id = :pokemon, pokestop_id = :pokestop, lat = :lat, lon = :lon, expire_timestamp = :exp, expire_timestamp_verified = :exp_ver,{}{} gender = :gender, cp = :cp, form = :form, costume = :costume, atk_iv = :atk_iv, def_iv = :def_iv, sta_iv = :sta_iv, move_1 = :move1, move_2 = :move2, weight = :weight, size = :size, capture_1 = :capture1, capture_2 = :capture2, capture_3 = :capture3, weather = :weather, level = :level, cell_id = :cell, username = :username, shiny = :shiny, display_pokemon_id = :display, is_event = :event, pvp_rankings_great_league = :great, pvp_rankings_ultra_league = :ultra;",
-            pokemon.last_modified_time.map(|_| " updated = :update,").unwrap_or_default(),
-            pokemon.first_seen.map(|_| " first_seen_timestamp = :first_seen,").unwrap_or_default(),
-        ),
-        params! {
-            "id" => pokemon.encounter_id.as_str(),
-            "pokemon" => pokemon.pokemon_id,
-            "pokestop" => pokemon.pokestop_id.as_ref().and_then(|id| if id == "None" { None } else { Some(id) }),
-            // "spawn" => pokemon.spawnpoint_id,
-            "lat" => pokemon.latitude,
-            "lon" => pokemon.longitude,
-            "exp" => pokemon.disappear_time,
-            "exp_ver" => pokemon.disappear_time_verified,
-            "update" => pokemon.last_modified_time,
-            "first_seen" => pokemon.first_seen.unwrap_or_else(|| Utc::now().timestamp()),
-            "gender" => pokemon.gender.get_id(),
-            "cp" => pokemon.cp,
-            "form" => pokemon.form,
-            "costume" => pokemon.costume,
-            "atk_iv" => pokemon.individual_attack,
-            "def_iv" => pokemon.individual_defense,
-            "sta_iv" => pokemon.individual_stamina,
-            "move1" => pokemon.move_1,
-            "move2" => pokemon.move_2,
-            "weight" => pokemon.weight,
-            "size" => pokemon.height,
-            "capture1" => pokemon.capture_1,
-            "capture2" => pokemon.capture_2,
-            "capture3" => pokemon.capture_3,
-            "weather" => pokemon.weather,
-            "level" => pokemon.pokemon_level,
-            "cell" => pokemon.s2_cell_id,
-            "username" => pokemon.username.as_ref(),
-            "shiny" => pokemon.shiny,
-            "display" => pokemon.display_pokemon_id,
-            "event" => pokemon.is_event.unwrap_or_default(),
-            "great" => pokemon.pvp_rankings_great_league.as_ref().and_then(|pvp| serde_json::to_string(pvp).ok()),
-            "ultra" => pokemon.pvp_rankings_ultra_league.as_ref().and_then(|pvp| serde_json::to_string(pvp).ok()),
-        })
+async fn update_pokemon(pokemon: &Pokemon, pvp: &Leagues) -> Result<(), ()> {
+    storage()
+        .upsert(
+            Upsert::new("pokemon")
+                .version("updated")
+                .key("id", pokemon.encounter_id.as_str())
+                .column("pokemon_id", pokemon.pokemon_id, Always)
+                .column("pokestop_id", pokemon.pokestop_id.as_deref().filter(|id| *id != "None"), Always)
+                .column("spawn_id", parse_spawnpoint_id(&pokemon.spawnpoint_id), Always)
+                .column("lat", pokemon.latitude, Always)
+                .column("lon", pokemon.longitude, Always)
+                .column("expire_timestamp", pokemon.disappear_time, Always)
+                .column("expire_timestamp_verified", pokemon.disappear_time_verified, Always)
+                .column("updated", pokemon.last_modified_time, IfNotNull)
+                .column_or("first_seen_timestamp", pokemon.first_seen, Utc::now().timestamp(), IfNotNull)
+                .column("gender", pokemon.gender.get_id(), Always)
+                .column("cp", pokemon.cp, Always)
+                .column("form", pokemon.form, Always)
+                .column("costume", pokemon.costume, Always)
+                .column("atk_iv", pokemon.individual_attack, Always)
+                .column("def_iv", pokemon.individual_defense, Always)
+                .column("sta_iv", pokemon.individual_stamina, Always)
+                .column("move_1", pokemon.move_1, Always)
+                .column("move_2", pokemon.move_2, Always)
+                .column("weight", pokemon.weight, Always)
+                .column("size", pokemon.height, Always)
+                .column("capture_1", pokemon.capture_1, Always)
+                .column("capture_2", pokemon.capture_2, Always)
+                .column("capture_3", pokemon.capture_3, Always)
+                .column("weather", pokemon.weather, Always)
+                .column("level", pokemon.pokemon_level, Always)
+                .column("cell_id", pokemon.s2_cell_id, Always)
+                .column("username", pokemon.username.as_deref(), Always)
+                .column("shiny", pokemon.shiny, Always)
+                .column("display_pokemon_id", pokemon.display_pokemon_id, Always)
+                .column("is_event", pokemon.is_event.unwrap_or_default(), Always)
+                .column(
+                    "pvp_rankings_great_league",
+                    pokemon.pvp_rankings_great_league.as_ref().and_then(|pvp| serde_json::to_string(pvp).ok()),
+                    Always,
+                )
+                .column(
+                    "pvp_rankings_ultra_league",
+                    pokemon.pvp_rankings_ultra_league.as_ref().and_then(|pvp| serde_json::to_string(pvp).ok()),
+                    Always,
+                ),
+        )
         .await
-        .map_err(|e| error!("Mysql update pokemon error: {}\n{:?}", e, pokemon))?;
+        .map_err(|e| error!("update pokemon error: {}\n{:?}", e, pokemon))?;
 
-    update_pokemon_stats(pokemon.pokemon_id);
+    update_spawnpoint(pokemon).await.ok();
 
-    update_city_stats(
-        (pokemon.latitude, pokemon.longitude).into(),
-        pokemon.pokemon_id,
-        pokemon.encounter_id.clone(),
-        Utc.timestamp_opt(pokemon.disappear_time, 0).single().ok_or(())?,
-    );
+    update_pokemon_pvp(pokemon, pvp).await.ok();
+
+    let point = (pokemon.latitude, pokemon.longitude).into();
+    let now = Utc::now();
+    let city_id = find_active_city(&point, now.timestamp());
+    // every daily table counts the pokemon into the day it was seen in
+    let day = stats::day(city_id, now);
+    stats::count_pokemon(day, now, pokemon.pokemon_id, pokemon.form.unwrap_or_default(), city_id);
+    let city_day = city_id.map(|city_id| (city_id, day));
+
+    archive_pokemon(pokemon, pvp, day).await;
+
+    update_city_stats(city_day, pokemon.pokemon_id, pokemon.encounter_id.clone());
+    if let (Some(atk), Some(def), Some(sta)) =
+        (pokemon.individual_attack, pokemon.individual_defense, pokemon.individual_stamina)
+    {
+        update_city_entity_stats(
+            city_day,
+            EntityStat::Iv,
+            pokemon.encounter_id.clone(),
+            i64::from(atk) + i64::from(def) + i64::from(sta),
+            Never,
+        );
+    }
+    if pokemon.shiny == Some(true) {
+        update_city_entity_stats(
+            city_day,
+            EntityStat::Shiny,
+            pokemon.encounter_id.clone(),
+            i64::from(pokemon.pokemon_id),
+            Never,
+        );
+    }
+
+    Ok(())
+}
 
+// scanners send "unknown" or an empty string when they don't have a value
+fn known(s: &str) -> Option<&str> {
+    if s.is_empty() || s.eq_ignore_ascii_case("unknown") {
+        None
+    } else {
+        Some(s)
+    }
+}
+
+/// spawnpoint ids come as hex strings, "None" when the scanner doesn't know it
+fn parse_spawnpoint_id(id: &str) -> Option<u64> {
+    u64::from_str_radix(id, 16).ok()
+}
+
+async fn update_spawnpoint(pokemon: &Pokemon) -> Result<(), ()> {
+    let Some(id) = parse_spawnpoint_id(&pokemon.spawnpoint_id) else {
+        return Ok(());
+    };
+
+    storage()
+        .upsert(
+            Upsert::new("spawnpoint")
+                .key("id", id)
+                .column("lat", pokemon.latitude, Always)
+                .column("lon", pokemon.longitude, Always)
+                .column("updated", Utc::now().timestamp(), Always)
+                .column("last_seen", pokemon.last_modified_time.unwrap_or_else(|| Utc::now().timestamp()), Always)
+                // an unverified despawn time is only an estimate, don't overwrite a learned one with it
+                .column(
+                    "despawn_sec",
+                    pokemon.disappear_time_verified.then(|| pokemon.disappear_time.rem_euclid(3600)),
+                    IfNotNull,
+                ),
+        )
+        .await
+        .map_err(|e| error!("update spawnpoint error: {}\n{:?}", e, pokemon))?;
+    Ok(())
+}
+
+async fn update_pokemon_pvp(pokemon: &Pokemon, pvp: &Leagues) -> Result<(), ()> {
+    // a pokemon seen again without an encounter keeps the rankings it already has
+    if pvp.is_empty() {
+        return Ok(());
+    }
+
+    storage()
+        .replace_pokemon_pvp(&pokemon.encounter_id, pvp)
+        .await
+        .map_err(|e| error!("update pokemon_pvp error: {}\n{:?}", e, pokemon))?;
     Ok(())
 }
 
 async fn update_quest(quest: &Quest) -> Result<(), ()> {
-    let mut conn = get_conn().await?;
-    conn.exec_drop(
-        format!(
-            "INSERT INTO pokestop (id, first_seen_timestamp, lat, lon, name, url, {alternative}quest_type, {alternative}quest_target, {alternative}quest_template, {alternative}quest_rewards, updated, {alternative}quest_conditions, {alternative}quest_timestamp, ar_scan_eligible)
-            VALUES (:id, UNIX_TIMESTAMP(), :lat, :lon, :name, :url, :type, :target, :template, :rewards, :updated, :conditions, UNIX_TIMESTAMP(), :ar)
-            ON DUPLICATE KEY UPDATE lat = :lat, lon = :lon,{}{} {alternative}quest_type = :type, {alternative}quest_target = :target, {alternative}quest_template = :template, {alternative}quest_rewards = :rewards, updated = :updated, {alternative}quest_conditions = :conditions, {alternative}quest_timestamp = UNIX_TIMESTAMP(), ar_scan_eligible = :ar;",
-            (!quest.pokestop_name.eq_ignore_ascii_case("unknown")).then_some(" name = :name,").unwrap_or_default(),
-            (!quest.pokestop_url.is_empty()).then_some(" url = :url,").unwrap_or_default(),
-            alternative = if quest.with_ar.unwrap_or_default() { "" } else { "alternative_" },
-        ),
-        params! {
-            "id" => quest.pokestop_id.as_str(),
-            "lat" => quest.latitude,
-            "lon" => quest.longitude,
-            "name" => quest.pokestop_name.as_str(),
-            "url" => quest.pokestop_url.as_str(),
-            "type" => quest._type,
-            "target" => quest.target,
-            "template" => quest.template.as_str(),
-            "rewards" => serde_json::to_string(&quest.rewards).ok(),
-            "updated" => quest.updated,
-            "conditions" => serde_json::to_string(&quest.conditions).ok(),
-            "ar" => quest.ar_scan_eligible,
-        })
+    let now = Utc::now().timestamp();
+    let alternative = if quest.with_ar.unwrap_or_default() { "" } else { "alternative_" };
+    storage()
+        .upsert(
+            Upsert::new("pokestop")
+                .version("updated")
+                .key("id", quest.pokestop_id.as_str())
+                .column("first_seen_timestamp", now, Never)
+                .column("lat", quest.latitude, Always)
+                .column("lon", quest.longitude, Always)
+                .column("name", known(&quest.pokestop_name), IfNotNull)
+                .column("url", known(&quest.pokestop_url), IfNotNull)
+                .column(format!("{}quest_type", alternative), quest._type, Always)
+                .column(format!("{}quest_target", alternative), quest.target, Always)
+                .column(format!("{}quest_template", alternative), quest.template.as_str(), Always)
+                .column(format!("{}quest_rewards", alternative), serde_json::to_string(&quest.rewards).ok(), Always)
+                .column("updated", quest.updated, Always)
+                .column(
+                    format!("{}quest_conditions", alternative),
+                    serde_json::to_string(&quest.conditions).ok(),
+                    Always,
+                )
+                .column(format!("{}quest_timestamp", alternative), now, Always)
+                .column("ar_scan_eligible", quest.ar_scan_eligible, Always),
+        )
         .await
-        .map_err(|e| error!("Mysql update quest error: {}\n{:?}", e, quest))?;
+        .map_err(|e| error!("update quest error: {}\n{:?}", e, quest))?;
+
+    let reward_type =
+        serde_json::to_value(&quest.rewards).ok().and_then(|rewards| rewards.get(0)?.get("type")?.as_i64());
+    if let Some(reward_type) = reward_type {
+        // a pokestop has a quest per day and AR mode, rescans replace it
+        update_city_entity_stats(
+            city_day(&(quest.latitude, quest.longitude).into(), Utc::now()),
+            EntityStat::QuestReward,
+            format!("{}:{}", quest.pokestop_id, if quest.with_ar.unwrap_or_default() { "ar" } else { "no_ar" }),
+            reward_type,
+            Always,
+        );
+    }
+
     Ok(())
 }
 
 async fn update_raid(raid: &Raid) -> Result<(), ()> {
-    let mut conn = get_conn().await?;
-    conn.exec_drop(
-        format!(
-            "INSERT INTO gym (id, updated, first_seen_timestamp, lat, lon, name, url, team_id, raid_spawn_timestamp, raid_battle_timestamp, raid_end_timestamp, raid_level, raid_pokemon_id, raid_pokemon_cp, raid_pokemon_move_1, raid_pokemon_move_2, ex_raid_eligible, raid_pokemon_form, raid_is_exclusive, raid_pokemon_gender, sponsor_id, raid_pokemon_evolution, ar_scan_eligible)
-            VALUES (:id, UNIX_TIMESTAMP(), UNIX_TIMESTAMP(), :lat, :lon, :name, :url, :team, :spawn, :start, :end, :level, :pokemon, :cp, :move1, :move2, :ex, :form, :is_ex, :gender, :sponsor, :evo, :ar)
-            ON DUPLICATE KEY UPDATE updated = UNIX_TIMESTAMP(), lat = :lat, lon = :lon,{}{} team_id = :team, raid_spawn_timestamp = :spawn, raid_battle_timestamp = :start, raid_end_timestamp = :end, raid_level = :level, raid_pokemon_id = :pokemon, raid_pokemon_cp = :cp, raid_pokemon_move_1 = :move1, raid_pokemon_move_2 = :move2, ex_raid_eligible = :ex, raid_pokemon_form = :form, raid_is_exclusive = :is_ex, raid_pokemon_gender = :gender, sponsor_id = :sponsor, raid_pokemon_evolution = :evo, ar_scan_eligible = :ar;",
-            (!raid.gym_name.eq_ignore_ascii_case("unknown")).then_some(" name = :name,").unwrap_or_default(),
-            (!raid.gym_url.is_empty()).then_some(" url = :url,").unwrap_or_default(),
-        ),
-        params! {
-            "id" => raid.gym_id.as_str(),
-            "lat" => raid.latitude,
-            "lon" => raid.longitude,
-            "name" => raid.gym_name.as_str(),
-            "url" => raid.gym_url.as_str(),
-            "team" => raid.team_id.get_id(),
-            "spawn" => raid.spawn,
-            "start" => raid.start,
-            "end" => raid.end,
-            "level" => raid.level,
-            "pokemon" => raid.pokemon_id,
-            "cp" => raid.cp,
-            "move1" => raid.move_1,
-            "move2" => raid.move_2,
-            "ex" => raid.ex_raid_eligible,
-            "form" => raid.form,
-            "is_ex" => raid.is_exclusive,
-            "gender" => raid.gender.as_ref().map(|g| g.get_id()),
-            "sponsor" => raid.sponsor_id,
-            "evo" => raid.evolution,
-            "ar" => raid.ar_scan_eligible,
-        })
+    let now = Utc::now().timestamp();
+    storage()
+        .upsert(
+            Upsert::new("gym")
+                .key("id", raid.gym_id.as_str())
+                .column("updated", now, Always)
+                .column("first_seen_timestamp", now, Never)
+                .column("lat", raid.latitude, Always)
+                .column("lon", raid.longitude, Always)
+                .column("name", known(&raid.gym_name), IfNotNull)
+                .column("url", known(&raid.gym_url), IfNotNull)
+                .column("team_id", raid.team_id.get_id(), Always)
+                .column("raid_spawn_timestamp", raid.spawn, Always)
+                .column("raid_battle_timestamp", raid.start, Always)
+                .column("raid_end_timestamp", raid.end, Always)
+                .column("raid_level", raid.level, Always)
+                .column("raid_pokemon_id", raid.pokemon_id, Always)
+                .column("raid_pokemon_cp", raid.cp, Always)
+                .column("raid_pokemon_move_1", raid.move_1, Always)
+                .column("raid_pokemon_move_2", raid.move_2, Always)
+                .column("ex_raid_eligible", raid.ex_raid_eligible, Always)
+                .column("raid_pokemon_form", raid.form, Always)
+                .column("raid_is_exclusive", raid.is_exclusive, Always)
+                .column("raid_pokemon_gender", raid.gender.as_ref().map(|g| g.get_id()), Always)
+                .column("sponsor_id", raid.sponsor_id, Always)
+                .column("raid_pokemon_evolution", raid.evolution, Always)
+                .column("ar_scan_eligible", raid.ar_scan_eligible, Always),
+        )
         .await
-        .map_err(|e| error!("Mysql update raid error: {}\n{:?}", e, raid))?;
+        .map_err(|e| error!("update raid error: {}\n{:?}", e, raid))?;
+
+    let point = (raid.latitude, raid.longitude).into();
+    update_gym_team_stats(point, &raid.gym_id, raid.team_id.get_id());
+    if let Some(spawn) = Utc.timestamp_opt(raid.spawn, 0).single() {
+        // a raid is identified by its gym and spawn time
+        update_city_entity_stats(
+            city_day(&point, spawn),
+            EntityStat::Raid,
+            format!("{}:{}", raid.gym_id, raid.spawn),
+            i64::from(raid.level),
+            Never,
+        );
+    }
+
     Ok(())
 }
 
-pub async fn submit<T: Iterator<Item = Request>>(iter: T) {
-    for request in iter {
-        tokio::spawn(async move {
+// where the entity is, for those having a location
+fn location(request: &Request) -> Option<Point<f64>> {
+    let (lat, lon) = match request {
+        Request::Gym(g) => (g.latitude, g.longitude),
+        Request::GymDetails(g) => (g.latitude, g.longitude),
+        Request::Invasion(p) | Request::Pokestop(p) => (p.latitude, p.longitude),
+        Request::Pokemon(p) => (p.latitude, p.longitude),
+        Request::Quest(q) => (q.latitude, q.longitude),
+        Request::Raid(r) => (r.latitude, r.longitude),
+        _ => return None,
+    };
+    Some((lat, lon).into())
+}
+
+// removes everything revealing the IVs of a pokemon, for cities not monitoring them
+fn strip_iv(pokemon: &mut Pokemon, pvp: &mut Leagues) {
+    pokemon.individual_attack = None;
+    pokemon.individual_defense = None;
+    pokemon.individual_stamina = None;
+    pokemon.cp = None;
+    pokemon.pokemon_level = None;
+    pokemon.pvp_rankings_great_league = None;
+    pokemon.pvp_rankings_ultra_league = None;
+    pvp.clear();
+}
+
+/// Runs a task that shutdown waits for, like the writes of the submitted requests
+pub fn spawn<F>(task: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    TASKS.spawn(task);
+}
+
+/// Waits for the writes of every submitted request, to be called once the server is down
+pub async fn drain() {
+    TASKS.close();
+    TASKS.wait().await;
+}
+
+pub async fn submit<T: Iterator<Item = (Request, Leagues)>>(iter: T) {
+    for (mut request, mut pvp) in iter {
+        let point = location(&request);
+        if point.is_some_and(|point| !geofence::accepts(&point)) {
+            continue;
+        }
+
+        if let (Request::Pokemon(pokemon), Some(point)) = (&mut request, point) {
+            if !scan_iv(&point, Utc::now().timestamp()) {
+                strip_iv(pokemon, &mut pvp);
+            }
+        }
+
+        TASKS.spawn(async move {
             match request {
                 Request::Gym(g) => {
                     update_gym(&g).await.ok();
@@ -272,7 +479,7 @@ pub async fn submit<T: Iterator<Item = Request>>(iter: T) {
                     update_pokestop(&p).await.ok();
                 }
                 Request::Pokemon(p) => {
-                    update_pokemon(&p).await.ok();
+                    update_pokemon(&p, &pvp).await.ok();
                 }
                 Request::Quest(q) => {
                     update_quest(&q).await.ok();
@@ -286,34 +493,101 @@ pub async fn submit<T: Iterator<Item = Request>>(iter: T) {
     }
 }
 
-fn update_pokemon_stats(pokemon_id: u16) {
-    tokio::spawn(async move {
-        if let Ok(mut conn) = get_conn().await {
-            conn.exec_drop("INSERT INTO pokemon_stats (`date`, `pokemon_id`, `count`) VALUES (CURDATE(), :pokemon_id, 1) ON DUPLICATE KEY UPDATE `count` = `count` + 1", params! {
-                    "pokemon_id" => pokemon_id,
-                }).await
-                .map_err(|e| error!("MySQL query error: insert pokemon stat\n{}", e)).ok();
-        }
+// the active city containing a point, with the day the given moment falls in there,
+// expired subscriptions don't collect stats anymore
+fn city_day(point: &Point<f64>, at: DateTime<Utc>) -> Option<(u16, NaiveDate)> {
+    let city_id = find_active_city(point, Utc::now().timestamp())?;
+    Some((city_id, stats::day(Some(city_id), at)))
+}
+
+fn update_city_stats(city_day: Option<(u16, NaiveDate)>, pokemon_id: u16, encounter_id: String) {
+    let Some((city_id, day)) = city_day else {
+        return;
+    };
+    TASKS.spawn(async move {
+        storage()
+            .upsert(
+                Upsert::new("city_stats_today")
+                    .key("day", day)
+                    .key("city_id", city_id)
+                    .key("encounter_id", encounter_id.as_str())
+                    .column("pokemon_id", pokemon_id, Always),
+            )
+            .await
+            .map_err(|e| error!("query error: insert park stat\n{}", e))
+            .ok();
     });
 }
 
-fn update_city_stats(point: Point<f64>, pokemon_id: u16, encounter_id: String, despawn: DateTime<Utc>) {
-    tokio::spawn(async move {
-        let city = {
-            let lock = CITIES.load();
-            lock.iter().find_map(|(id, city)| if city.coordinates.within(&point) { Some(*id) } else { None })
+fn update_city_lure_stats(point: Point<f64>, lure_id: u16, start: i64) {
+    TASKS.spawn(async move {
+        let Some(start) = Utc.timestamp_opt(start, 0).single() else {
+            return;
         };
 
-        if let Some(city_id) = city {
-            if let Ok(mut conn) = get_conn().await {
-                conn.exec_drop("REPLACE INTO city_stats_today (day, city_id, encounter_id, pokemon_id) VALUES (:day, :city_id, :encounter_id, :pokemon_id)", params! {
-                        "day" => despawn.date_naive(),
-                        "city_id" => city_id,
-                        "encounter_id" => encounter_id.as_str(),
-                        "pokemon_id" => pokemon_id,
-                    }).await
-                    .map_err(|e| error!("MySQL query error: insert park stat\n{}", e)).ok();
-            }
+        if let Some(city_id) = find_active_city(&point, Utc::now().timestamp()) {
+            storage()
+                .update_city_lure_stats(stats::day(Some(city_id), start), city_id, lure_id)
+                .await
+                .map_err(|e| error!("query error: insert lure stat\n{}", e))
+                .ok();
         }
     });
 }
+
+// one row per entity and day, so that an entity sent many times is counted once
+fn update_city_entity_stats(
+    city_day: Option<(u16, NaiveDate)>,
+    stat: EntityStat,
+    entity_id: String,
+    value: i64,
+    policy: MergePolicy,
+) {
+    let Some((city_id, day)) = city_day else {
+        return;
+    };
+    TASKS.spawn(async move {
+        storage()
+            .upsert(
+                Upsert::new("city_entity_stats")
+                    .key("day", day)
+                    .key("city_id", city_id)
+                    .key("kind", stat.kind())
+                    .key("entity_id", entity_id.as_str())
+                    .column("value", value, policy),
+            )
+            .await
+            .map_err(|e| error!("query error: insert {} stat\n{}", stat.kind(), e))
+            .ok();
+    });
+}
+
+// the team controlling a gym can change during the day, the last one seen wins
+fn update_gym_team_stats(point: Point<f64>, gym_id: &str, team_id: u8) {
+    update_city_entity_stats(
+        city_day(&point, Utc::now()),
+        EntityStat::GymTeam,
+        gym_id.to_owned(),
+        i64::from(team_id),
+        Always,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_values() {
+        assert_eq!(known("Fontana"), Some("Fontana"));
+        assert_eq!(known(" "), Some(" "));
+    }
+
+    #[test]
+    fn unknown_values() {
+        assert_eq!(known(""), None);
+        assert_eq!(known("unknown"), None);
+        assert_eq!(known("Unknown"), None);
+        assert_eq!(known("UNKNOWN"), None);
+    }
+}
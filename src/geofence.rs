@@ -0,0 +1,75 @@
+use chrono::Utc;
+
+use geo::{Contains, Point, Polygon};
+
+use once_cell::sync::Lazy;
+
+use tracing::debug;
+
+use crate::{
+    config::{Area, GeofenceAction, CONFIG},
+    lists::{find_city, in_expired_city},
+    metrics::GEOFENCE,
+};
+
+struct Fence {
+    name: String,
+    polygon: Polygon<f64>,
+}
+
+impl From<&Area> for Fence {
+    fn from(area: &Area) -> Self {
+        let points: Vec<Point<f64>> = area.points.iter().map(|[lat, lon]| Point::new(*lat, *lon)).collect();
+        Fence { name: area.name.clone(), polygon: Polygon::new(points.into(), vec![]) }
+    }
+}
+
+static INCLUDE: Lazy<Vec<Fence>> =
+    Lazy::new(|| CONFIG.geofence.iter().flat_map(|geofence| geofence.include.iter().map(Fence::from)).collect());
+
+static EXCLUDE: Lazy<Vec<Fence>> =
+    Lazy::new(|| CONFIG.geofence.iter().flat_map(|geofence| geofence.exclude.iter().map(Fence::from)).collect());
+
+// the reason a point is refused, if it is
+fn check(point: &Point<f64>) -> Option<String> {
+    let geofence = CONFIG.geofence.as_ref()?;
+
+    if let Some(fence) = EXCLUDE.iter().find(|fence| fence.polygon.contains(point)) {
+        return Some(format!("inside excluded area \"{}\"", fence.name));
+    }
+    if geofence.expired_cities && in_expired_city(point, Utc::now().timestamp()) {
+        return Some(String::from("inside an expired city"));
+    }
+
+    // without any inclusion rule everything not excluded is welcome
+    if !geofence.cities && INCLUDE.is_empty() {
+        return None;
+    }
+    if geofence.cities && find_city(point).is_some() {
+        return None;
+    }
+    if INCLUDE.iter().any(|fence| fence.polygon.contains(point)) {
+        return None;
+    }
+    Some(String::from("outside every city and included area"))
+}
+
+/// Tells if an entity at the given point has to be written
+pub fn accepts(point: &Point<f64>) -> bool {
+    let Some(reason) = check(point) else {
+        return true;
+    };
+
+    match CONFIG.geofence.as_ref().map(|geofence| geofence.action) {
+        Some(GeofenceAction::Log) => {
+            debug!("entity at {:?} {}, writing it anyway", point.x_y(), reason);
+            GEOFENCE.logged();
+            true
+        }
+        _ => {
+            debug!("entity at {:?} {}, dropping it", point.x_y(), reason);
+            GEOFENCE.dropped();
+            false
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use std::{iter::Peekable, str::Chars};
+
+use geo::{Coord, LineString, MultiPolygon, Polygon};
+
+use serde_json::Value;
+
+// points are stored as (lat, lon), the order used by the legacy city format and by the engine
+fn coord(lat: f64, lon: f64) -> Coord<f64> {
+    Coord { x: lat, y: lon }
+}
+
+/// Parses a city geometry written as WKT, GeoJSON or legacy `(lat,lon),(lat,lon)` list.
+///
+/// WKT and GeoJSON follow the standard longitude, latitude order.
+pub fn parse(s: &str) -> Result<MultiPolygon<f64>, String> {
+    let s = s.trim();
+    let polygons = if s.is_empty() {
+        return Err(String::from("empty geometry"));
+    } else if s.starts_with('{') {
+        geojson(s)?
+    } else if s.starts_with('(') {
+        vec![legacy(s)?]
+    } else {
+        wkt(s)?
+    };
+    validate(polygons)
+}
+
+/// Reads an already parsed GeoJSON geometry, feature or feature collection
+pub fn from_geojson(value: &Value) -> Result<MultiPolygon<f64>, String> {
+    validate(geojson_polygons(value)?)
+}
+
+/// Writes a geometry as a WKT MULTIPOLYGON, in longitude, latitude order
+pub fn to_wkt(geometry: &MultiPolygon<f64>) -> String {
+    let ring = |ring: &LineString<f64>| {
+        let coords: Vec<String> = ring.coords().map(|c| format!("{} {}", c.y, c.x)).collect();
+        format!("({})", coords.join(", "))
+    };
+    let polygons: Vec<String> = geometry
+        .iter()
+        .map(|polygon| {
+            let rings: Vec<String> = std::iter::once(polygon.exterior()).chain(polygon.interiors()).map(ring).collect();
+            format!("({})", rings.join(", "))
+        })
+        .collect();
+    format!("MULTIPOLYGON({})", polygons.join(", "))
+}
+
+fn validate(polygons: Vec<Polygon<f64>>) -> Result<MultiPolygon<f64>, String> {
+    for polygon in &polygons {
+        for ring in std::iter::once(polygon.exterior()).chain(polygon.interiors()) {
+            validate_ring(ring)?;
+        }
+    }
+    Ok(MultiPolygon(polygons))
+}
+
+fn validate_ring(ring: &LineString<f64>) -> Result<(), String> {
+    if let Some(c) = ring.coords().find(|c| !(-90.0..=90.0).contains(&c.x) || !(-180.0..=180.0).contains(&c.y)) {
+        return Err(format!("coordinate ({}, {}) out of range", c.x, c.y));
+    }
+    // rings are closed, the first point is repeated at the end
+    if ring.coords().count() < 4 {
+        return Err(String::from("a ring needs at least 3 points"));
+    }
+    Ok(())
+}
+
+fn number(s: &str) -> Result<f64, String> {
+    s.parse::<f64>().ok().filter(|f| f.is_finite()).ok_or_else(|| format!("\"{}\" isn't a valid number", s))
+}
+
+fn legacy(s: &str) -> Result<Polygon<f64>, String> {
+    let s = s.replace(char::is_whitespace, "");
+    let inner = s.strip_prefix('(').and_then(|s| s.strip_suffix(')')).ok_or("unbalanced parenthesis")?;
+    let coords = inner
+        .split("),(")
+        .map(|pair| match pair.split_once(',') {
+            Some((lat, lon)) => Ok(coord(number(lat)?, number(lon)?)),
+            None => Err(format!("\"{}\" isn't a lat,lon pair", pair)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Polygon::new(coords.into(), vec![]))
+}
+
+// WKT nested lists, leaves are coordinates
+enum Node {
+    List(Vec<Node>),
+    Coord(Coord<f64>),
+}
+
+fn wkt(s: &str) -> Result<Vec<Polygon<f64>>, String> {
+    // EWKT prefix
+    let s = s.split_once(';').map(|(srid, wkt)| if srid.starts_with("SRID=") { wkt } else { s }).unwrap_or(s);
+    let index = s.find('(').ok_or("missing coordinates")?;
+    let kind = s[..index].trim().to_ascii_uppercase();
+
+    let mut chars = s[index..].chars().peekable();
+    let node = wkt_list(&mut chars)?;
+    if chars.any(|c| !c.is_whitespace()) {
+        return Err(String::from("trailing characters after geometry"));
+    }
+
+    match (kind.as_str(), node) {
+        ("POLYGON", Node::List(rings)) => Ok(vec![wkt_polygon(rings)?]),
+        ("MULTIPOLYGON", Node::List(polygons)) => polygons
+            .into_iter()
+            .map(|polygon| match polygon {
+                Node::List(rings) => wkt_polygon(rings),
+                Node::Coord(_) => Err(String::from("expected a polygon")),
+            })
+            .collect(),
+        _ => Err(format!("unsupported geometry type \"{}\"", kind)),
+    }
+}
+
+fn wkt_list(chars: &mut Peekable<Chars>) -> Result<Node, String> {
+    skip_whitespace(chars);
+    if chars.next() != Some('(') {
+        return Err(String::from("expected '('"));
+    }
+
+    let mut items = Vec::new();
+    loop {
+        skip_whitespace(chars);
+        if chars.peek() == Some(&'(') {
+            items.push(wkt_list(chars)?);
+        } else {
+            let mut token = String::new();
+            while let Some(c) = chars.next_if(|c| *c != ',' && *c != ')') {
+                token.push(c);
+            }
+            let mut parts = token.split_whitespace();
+            match (parts.next(), parts.next()) {
+                // WKT is x y, that is lon lat
+                (Some(lon), Some(lat)) => items.push(Node::Coord(coord(number(lat)?, number(lon)?))),
+                _ => return Err(format!("\"{}\" isn't a coordinate", token.trim())),
+            }
+        }
+        skip_whitespace(chars);
+        match chars.next() {
+            Some(',') => continue,
+            Some(')') => return Ok(Node::List(items)),
+            _ => return Err(String::from("unbalanced parenthesis")),
+        }
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+fn wkt_ring(node: Node) -> Result<LineString<f64>, String> {
+    match node {
+        Node::List(coords) => coords
+            .into_iter()
+            .map(|c| match c {
+                Node::Coord(c) => Ok(c),
+                Node::List(_) => Err(String::from("expected a coordinate")),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(LineString::from),
+        Node::Coord(_) => Err(String::from("expected a ring")),
+    }
+}
+
+fn wkt_polygon(rings: Vec<Node>) -> Result<Polygon<f64>, String> {
+    let mut rings = rings.into_iter().map(wkt_ring);
+    let exterior = rings.next().ok_or("polygon without rings")??;
+    Ok(Polygon::new(exterior, rings.collect::<Result<_, _>>()?))
+}
+
+fn geojson(s: &str) -> Result<Vec<Polygon<f64>>, String> {
+    let value: Value = serde_json::from_str(s).map_err(|e| format!("invalid GeoJSON: {}", e))?;
+    geojson_polygons(&value)
+}
+
+fn geojson_polygons(value: &Value) -> Result<Vec<Polygon<f64>>, String> {
+    match value.get("type").and_then(Value::as_str) {
+        Some("Feature") => geojson_polygons(value.get("geometry").ok_or("feature without geometry")?),
+        Some("FeatureCollection") => {
+            let features = value.get("features").and_then(Value::as_array).ok_or("collection without features")?;
+            features.iter().map(geojson_polygons).collect::<Result<Vec<_>, _>>().map(|p| p.concat())
+        }
+        Some("Polygon") => Ok(vec![geojson_polygon(value.get("coordinates").ok_or("polygon without coordinates")?)?]),
+        Some("MultiPolygon") => value
+            .get("coordinates")
+            .and_then(Value::as_array)
+            .ok_or("multipolygon without coordinates")?
+            .iter()
+            .map(geojson_polygon)
+            .collect(),
+        other => Err(format!("unsupported GeoJSON type {:?}", other)),
+    }
+}
+
+fn geojson_polygon(value: &Value) -> Result<Polygon<f64>, String> {
+    let mut rings = value.as_array().ok_or("polygon coordinates must be an array")?.iter().map(|ring| {
+        ring.as_array()
+            .ok_or("ring must be an array")?
+            .iter()
+            .map(|position| match position.as_array().map(Vec::as_slice) {
+                // GeoJSON positions are [lon, lat]
+                Some([lon, lat, ..]) => match (lon.as_f64(), lat.as_f64()) {
+                    (Some(lon), Some(lat)) => Ok(coord(lat, lon)),
+                    _ => Err(format!("{} isn't a valid position", position)),
+                },
+                _ => Err(format!("{} isn't a valid position", position)),
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map(LineString::from)
+    });
+    let exterior = rings.next().ok_or("polygon without rings")??;
+    Ok(Polygon::new(exterior, rings.collect::<Result<_, _>>()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use geo::{Contains, Point};
+
+    use super::*;
+
+    const SQUARE: &str = "POLYGON((9 45, 10 45, 10 46, 9 46, 9 45))";
+
+    const WITH_HOLE: &str = "MULTIPOLYGON(((0 0, 10 0, 10 10, 0 10, 0 0), (4 4, 6 4, 6 6, 4 6, 4 4)), \
+                             ((20 20, 21 20, 21 21, 20 20)))";
+
+    #[test]
+    fn wkt_round_trip() {
+        let geometry = parse(WITH_HOLE).unwrap();
+        let wkt = to_wkt(&geometry);
+        assert_eq!(
+            wkt,
+            "MULTIPOLYGON(((0 0, 10 0, 10 10, 0 10, 0 0), (4 4, 6 4, 6 6, 4 6, 4 4)), ((20 20, 21 20, 21 21, 20 20)))"
+        );
+        assert_eq!(parse(&wkt).unwrap(), geometry);
+    }
+
+    #[test]
+    fn geojson_round_trip() {
+        let geojson = r#"{"type": "Feature", "geometry": {"type": "Polygon",
+            "coordinates": [[[9, 45], [10, 45], [10, 46], [9, 46], [9, 45]]]}}"#;
+        let geometry = parse(geojson).unwrap();
+        assert_eq!(geometry, parse(SQUARE).unwrap());
+        assert_eq!(parse(&to_wkt(&geometry)).unwrap(), geometry);
+        assert_eq!(from_geojson(&serde_json::from_str(geojson).unwrap()).unwrap(), geometry);
+    }
+
+    #[test]
+    fn axis_order() {
+        // points are (lat, lon) whatever the format
+        let inside = Point::new(45.5, 9.5);
+        let swapped = Point::new(9.5, 45.5);
+        let geojson = r#"{"type": "Polygon", "coordinates": [[[9, 45], [10, 45], [10, 46], [9, 46], [9, 45]]]}"#;
+        let legacy = "(45,9),(45,10),(46,10),(46,9)";
+        for s in [SQUARE, geojson, legacy] {
+            let geometry = parse(s).unwrap();
+            assert!(geometry.contains(&inside), "{}", s);
+            assert!(!geometry.contains(&swapped), "{}", s);
+        }
+    }
+
+    #[test]
+    fn unclosed_ring() {
+        // rings are closed on parsing
+        let geometry = parse("POLYGON((9 45, 10 45, 10 46, 9 46))").unwrap();
+        assert_eq!(geometry, parse(SQUARE).unwrap());
+        let geojson = r#"{"type": "Polygon", "coordinates": [[[9, 45], [10, 45], [10, 46], [9, 46]]]}"#;
+        assert_eq!(parse(geojson).unwrap(), geometry);
+    }
+
+    #[test]
+    fn too_short_ring() {
+        assert!(parse("POLYGON((9 45, 10 45, 9 45))").is_err());
+        assert!(parse("POLYGON((9 45, 10 45))").is_err());
+        assert!(parse(r#"{"type": "Polygon", "coordinates": [[[9, 45], [10, 45], [9, 45]]]}"#).is_err());
+        // holes too
+        assert!(parse("POLYGON((0 0, 10 0, 10 10, 0 0), (1 1, 2 2, 1 1))").is_err());
+        assert!(parse("(45,9),(45,10)").is_err());
+    }
+
+    #[test]
+    fn out_of_range() {
+        assert!(parse("POLYGON((9 91, 10 45, 10 46, 9 91))").is_err());
+        assert!(parse("POLYGON((181 45, 10 45, 10 46, 181 45))").is_err());
+        assert!(parse(r#"{"type": "Polygon", "coordinates": [[[9, -91], [10, 45], [10, 46], [9, -91]]]}"#).is_err());
+        assert!(parse("(45,-181),(45,10),(46,10)").is_err());
+        assert!(parse("POLYGON((180 90, -180 90, -180 -90, 180 90))").is_ok());
+    }
+
+    #[test]
+    fn multipolygon_with_holes() {
+        let geojson = r#"{"type": "MultiPolygon", "coordinates": [
+            [[[0, 0], [10, 0], [10, 10], [0, 10], [0, 0]], [[4, 4], [6, 4], [6, 6], [4, 6], [4, 4]]],
+            [[[20, 20], [21, 20], [21, 21], [20, 20]]]
+        ]}"#;
+        for s in [WITH_HOLE, geojson] {
+            let geometry = parse(s).unwrap();
+            assert_eq!(geometry.0.len(), 2);
+            assert_eq!(geometry.0[0].interiors().len(), 1);
+            assert!(geometry.contains(&Point::new(2.0, 2.0)));
+            assert!(!geometry.contains(&Point::new(5.0, 5.0)));
+            assert!(geometry.contains(&Point::new(20.2, 20.5)));
+        }
+    }
+}
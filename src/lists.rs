@@ -1,113 +1,358 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, fmt, path::Path, sync::Arc};
 
 use arc_swap::ArcSwap;
 
-use futures_util::TryStreamExt;
+use chrono_tz::Tz;
 
-use geo::{Point, Polygon};
+use geo::{BoundingRect, Contains, MultiPolygon, Point};
 
-use mysql_async::{
-    prelude::{FromRow, Queryable},
-    Row,
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::{
+    sync::Mutex,
+    time::{interval_at, Duration, Instant},
 };
 
-use tokio::time::{interval_at, Duration, Instant};
-
 use once_cell::sync::Lazy;
 
-use tracing::error;
+use serde::Serialize;
+
+use rstar::{primitives::GeomWithData, primitives::Rectangle, RTree, AABB};
+
+use tracing::{error, info, warn};
+
+use crate::{
+    city_files,
+    config::{CitySource, CONFIG},
+    db::reader,
+    geometry,
+    storage::Storage,
+};
+
+pub static CITIES: Lazy<ArcSwap<Cities>> = Lazy::new(Default::default);
+
+// reloads come from the watcher, signals and the admin API, one at a time
+static RELOAD: Lazy<Mutex<()>> = Lazy::new(Default::default);
+
+/// Loaded cities, indexed by their bounding boxes
+#[derive(Default)]
+pub struct Cities {
+    pub cities: HashMap<u16, City>,
+    index: RTree<GeomWithData<Rectangle<[f64; 2]>, u16>>,
+}
+
+impl Cities {
+    fn new(cities: HashMap<u16, City>) -> Self {
+        let boxes = cities
+            .values()
+            .filter_map(|city| {
+                let rect = city.coordinates.bounding_rect()?;
+                let aabb = AABB::from_corners(rect.min().x_y().into(), rect.max().x_y().into());
+                Some(GeomWithData::new(Rectangle::from_aabb(aabb), city.id))
+            })
+            .collect();
+        Cities { cities, index: RTree::bulk_load(boxes) }
+    }
 
-use crate::db::get_conn;
+    /// Returns the city containing the given point among the ones satisfying `filter`, if any
+    pub fn find_by(&self, point: &Point<f64>, filter: impl Fn(&City) -> bool) -> Option<&City> {
+        // only the polygons whose bounding box contains the point need the full check,
+        // the lowest id wins when cities overlap
+        self.index
+            .locate_all_at_point(&point.x_y().into())
+            .filter_map(|candidate| self.cities.get(&candidate.data))
+            .filter(|city| filter(city) && city.coordinates.contains(point))
+            .min_by_key(|city| city.id)
+    }
 
-pub static CITIES: Lazy<ArcSwap<HashMap<u16, City>>> = Lazy::new(Default::default);
+    /// Returns the id of the city containing the given point, if any
+    pub fn find(&self, point: &Point<f64>) -> Option<u16> {
+        self.find_by(point, |_| true).map(|city| city.id)
+    }
+}
 
-#[allow(dead_code)]
+#[derive(PartialEq)]
 pub struct City {
     pub id: u16,
     pub name: String,
-    pub coordinates: Polygon<f64>,
+    pub coordinates: MultiPolygon<f64>,
     pub scadenza: i64,
     pub scan_iv: u8,
     pub admins_users: Vec<String>,
+    /// stats days follow it, the configured default is used when missing
+    pub timezone: Option<Tz>,
 }
 
-impl FromRow for City {
-    fn from_row_opt(mut row: Row) -> Result<Self, mysql_async::FromRowError> {
-        let id = row.take("id").expect("MySQL city.id error");
-        let name = row.take("name").expect("MySQL city.name error");
-        let coords = row.take::<String, _>("coordinates").expect("MySQL city.coordinates encoding error");
-        let coords = coords.replace(char::is_whitespace, "");
-
-        let poly: Vec<Point<f64>> = if coords.len() < 2 {
-            error!("City \"{}\" ({}) has empty coordinates", name, id);
-            Vec::new()
-        } else {
-            coords[1..(coords.len() - 1)]
-                .split("),(")
-                .filter_map(|s| {
-                    let x_y: Vec<f64> = s
-                        .split(',')
-                        .map(|s| match s.parse::<f64>() {
-                            Ok(f) => f,
-                            Err(_) => panic!("Error parsing \"{}\" as a float", s),
-                        })
-                        .collect();
-                    if x_y.len() == 2 {
-                        Some(Point::new(x_y[0], x_y[1]))
-                    } else {
-                        error!("City \"{}\" ({}) has invalid coordinates", name, id);
-                        None
-                    }
-                })
-                .collect()
-        };
-
+impl City {
+    /// Builds a city from its raw database columns
+    pub fn new(
+        id: u16,
+        name: String,
+        coords: &str,
+        scadenza: i64,
+        scan_iv: u8,
+        admins_users: &str,
+        timezone: Option<&str>,
+    ) -> Result<Self, String> {
+        let coordinates = geometry::parse(coords).map_err(|e| format!("\"{}\" geometry: {}", name, e))?;
+        let timezone = parse_timezone(timezone).map_err(|e| format!("\"{}\" {}", name, e))?;
         Ok(City {
             id,
             name,
-            coordinates: Polygon::new(poly.into(), vec![]),
-            scadenza: row.take("scadenza").expect("MySQL city.scadenza error"),
-            scan_iv: row.take("monitor").expect("MySQL city.monitor error"),
-            admins_users: row
-                .take::<String, _>("admins_users")
-                .expect("MySQL city.admins_users error")
-                .split_whitespace()
-                .map(|s| s.to_owned())
-                .collect(),
+            coordinates,
+            scadenza,
+            scan_iv,
+            admins_users: admins_users.split_whitespace().map(|s| s.to_owned()).collect(),
+            timezone,
         })
     }
+
+    /// Tells if the city subscription (`scadenza`) is still running at the given timestamp
+    pub fn active(&self, now: i64) -> bool {
+        self.scadenza >= now
+    }
 }
 
-pub async fn load_cities() -> Result<(), ()> {
-    let mut conn = get_conn().await?;
-    let res = conn
-        .query_iter("SELECT id, name, coordinates, scadenza, monitor, admins_users FROM city")
-        .await
-        .map_err(|e| error!("MySQL query error: get cities\n{}", e))?;
+/// Parses an IANA timezone name, empty values mean no timezone
+pub fn parse_timezone(timezone: Option<&str>) -> Result<Option<Tz>, String> {
+    match timezone.map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(timezone) => timezone.parse().map(Some).map_err(|_| format!("unknown timezone \"{}\"", timezone)),
+    }
+}
 
-    let data = res
-        .stream_and_drop::<City>()
-        .await
-        .map_err(|e| error!("MySQL load_cities error: {}", e))?
-        .ok_or_else(|| error!("MySQL load_cities empty"))?
-        .map_ok(|c| (c.id, c))
-        .try_collect()
-        .await
-        .map_err(|e| error!("MySQL load_cities collect error: {}", e))?;
-    CITIES.swap(Arc::new(data));
+/// Returns the id of the city containing the given point, if any
+pub fn find_city(point: &Point<f64>) -> Option<u16> {
+    CITIES.load().find(point)
+}
 
-    Ok(())
+/// Returns the id of the city containing the given point whose subscription is still running, if any
+pub fn find_active_city(point: &Point<f64>, now: i64) -> Option<u16> {
+    CITIES.load().find_by(point, |city| city.active(now)).map(|city| city.id)
+}
+
+/// Tells if the point is only covered by cities whose subscription is over
+pub fn in_expired_city(point: &Point<f64>, now: i64) -> bool {
+    let cities = CITIES.load();
+    cities.find(point).is_some() && cities.find_by(point, |city| city.active(now)).is_none()
+}
+
+/// Tells if pokemon IVs have to be stored at the given point, that is outside cities with `monitor` set to 0
+pub fn scan_iv(point: &Point<f64>, now: i64) -> bool {
+    // the settings of a running subscription win over expired ones
+    let cities = CITIES.load();
+    cities
+        .find_by(point, |city| city.active(now))
+        .or_else(|| cities.find_by(point, |_| true))
+        .is_none_or(|city| city.scan_iv > 0)
+}
+
+/// Ids of the cities touched by a reload
+#[derive(Default, Serialize)]
+pub struct Changes {
+    pub added: Vec<u16>,
+    pub removed: Vec<u16>,
+    pub modified: Vec<u16>,
+    /// cities skipped because malformed
+    pub invalid: Vec<String>,
+}
+
+impl Changes {
+    fn new(old: &HashMap<u16, City>, new: &HashMap<u16, City>) -> Self {
+        let mut changes = Changes::default();
+        for (id, city) in new {
+            match old.get(id) {
+                None => changes.added.push(*id),
+                Some(old) if old != city => changes.modified.push(*id),
+                Some(_) => {}
+            }
+        }
+        changes.removed = old.keys().filter(|id| !new.contains_key(id)).copied().collect();
+        changes.added.sort_unstable();
+        changes.removed.sort_unstable();
+        changes.modified.sort_unstable();
+        changes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty()
+    }
+}
+
+impl fmt::Display for Changes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "added {:?}, removed {:?}, modified {:?}", self.added, self.removed, self.modified)
+    }
+}
+
+/// Reloads the cities from the configured sources, `reason` is only logged
+///
+/// When a source can't be read the current cities are kept, malformed cities are skipped.
+pub async fn load_cities(reason: &str) -> Result<Changes, ()> {
+    load_cities_from(reader(), reason).await
 }
 
-pub async fn init() {
+/// Like `load_cities`, reading the database cities from `source`, the main storage right after writing them
+pub async fn load_cities_from(source: &dyn Storage, reason: &str) -> Result<Changes, ()> {
+    // an older read must not be swapped in after a newer one, nor be diffed against it
+    let _reload = RELOAD.lock().await;
+    let files = CONFIG.city_files.as_ref();
+    let mut rows = Vec::new();
+    if files.is_none_or(|files| files.source == CitySource::Merge) {
+        rows.extend(source.load_cities().await.map_err(|e| error!("load_cities error: {}, previous cities kept", e))?);
+    }
+    if let Some(files) = files {
+        // file cities come last, so they win over database ones with the same id
+        rows.extend(
+            city_files::load(Path::new(&files.path))
+                .await
+                .map_err(|e| error!("load_cities error: {}, previous cities kept", e))?,
+        );
+    }
+
+    let mut data = HashMap::new();
+    let mut invalid = Vec::new();
+    for row in rows {
+        match row {
+            Ok(city) => {
+                data.insert(city.id, city);
+            }
+            Err(e) => {
+                warn!("load_cities skipped invalid {}", e);
+                invalid.push(e);
+            }
+        }
+    }
+
+    let mut changes = Changes::new(&CITIES.load().cities, &data);
+    changes.invalid = invalid;
+    CITIES.swap(Arc::new(Cities::new(data)));
+
+    if !changes.is_empty() {
+        info!("cities reloaded ({}): {}", reason, changes);
+    }
+    Ok(changes)
+}
+
+// reloads the cities as soon as the files change
+async fn watch(path: &'static Path, period: Duration) {
+    let mut last = city_files::fingerprint(path).await.ok();
+    let mut interval = interval_at(Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        let current = match city_files::fingerprint(path).await {
+            Ok(current) => Some(current),
+            Err(e) => {
+                // logged once, until the files are readable again
+                if last.take().is_some() {
+                    error!("city files watch error: {}", e);
+                }
+                continue;
+            }
+        };
+        // a broken file keeps the previous cities until it's changed again
+        if current != last {
+            last = current;
+            load_cities("files changed").await.ok();
+        }
+    }
+}
+
+pub async fn init() -> Result<(), ()> {
     // force first load
-    load_cities().await.unwrap();
-    tokio::spawn(async {
-        let period = Duration::from_secs(1800);
-        let mut interval = interval_at(Instant::now() + period, period);
-        loop {
-            interval.tick().await;
-            load_cities().await.ok();
+    if load_cities("startup").await.is_err() {
+        if !CONFIG.service.cities_optional {
+            error!("cities can't be loaded, set `cities_optional` to start anyway");
+            return Err(());
+        }
+        warn!("starting without cities, retrying every minute");
+        tokio::spawn(async {
+            let period = Duration::from_secs(60);
+            let mut interval = interval_at(Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                if load_cities("startup retry").await.is_ok() {
+                    break;
+                }
+            }
+        });
+    }
+
+    let period = CONFIG.service.cities_reload.unwrap_or(1800);
+    if period > 0 {
+        tokio::spawn(async move {
+            let period = Duration::from_secs(period);
+            let mut interval = interval_at(Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                load_cities("periodic").await.ok();
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    match signal(SignalKind::hangup()) {
+        Ok(mut hangup) => {
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    load_cities("SIGHUP").await.ok();
+                }
+            });
         }
-    });
+        Err(e) => error!("SIGHUP handler error: {}", e),
+    }
+
+    if let Some(files) = CONFIG.city_files.as_ref() {
+        let period = files.watch.unwrap_or(10);
+        if period > 0 {
+            tokio::spawn(watch(Path::new(&files.path), Duration::from_secs(period)));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn city(id: u16, scadenza: i64) -> (u16, City) {
+        let coords = "POLYGON((9 45, 10 45, 10 46, 9 46, 9 45))";
+        (id, City::new(id, format!("city {}", id), coords, scadenza, 1, "", None).unwrap())
+    }
+
+    #[test]
+    fn no_changes() {
+        let old = HashMap::from([city(1, 10), city(2, 10)]);
+        let new = HashMap::from([city(2, 10), city(1, 10)]);
+        assert!(Changes::new(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn added_removed_modified() {
+        let old = HashMap::from([city(1, 10), city(2, 10), city(3, 10), city(5, 10)]);
+        let new = HashMap::from([city(5, 10), city(3, 20), city(4, 10), city(1, 10), city(6, 10)]);
+        let changes = Changes::new(&old, &new);
+        assert_eq!(changes.added, [4, 6]);
+        assert_eq!(changes.removed, [2]);
+        assert_eq!(changes.modified, [3]);
+        assert!(!changes.is_empty());
+    }
+
+    #[test]
+    fn from_and_to_nothing() {
+        let cities = HashMap::from([city(2, 10), city(1, 10)]);
+        let changes = Changes::new(&HashMap::new(), &cities);
+        assert_eq!((changes.added, changes.removed, changes.modified), (vec![1, 2], vec![], vec![]));
+        let changes = Changes::new(&cities, &HashMap::new());
+        assert_eq!((changes.added, changes.removed, changes.modified), (vec![], vec![1, 2], vec![]));
+    }
+
+    #[test]
+    fn timezone_change_is_a_modification() {
+        let old = HashMap::from([city(1, 10)]);
+        let mut new = HashMap::from([city(1, 10)]);
+        new.get_mut(&1).unwrap().timezone = Some(Tz::Europe__Rome);
+        assert_eq!(Changes::new(&old, &new).modified, [1]);
+    }
 }
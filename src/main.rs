@@ -5,21 +5,36 @@
 //!
 //! Map feeder via RocketMap webhooks
 
+use std::{env, process, time::Duration};
+
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server};
 
 use futures_util::TryStreamExt;
 
-use tokio::spawn;
-
 use serde_json::value::Value;
 
-use tracing::{debug, error, info};
+use tokio::time::{interval_at, Instant};
+
+use tracing::{debug, error, info, warn};
 
+mod admin;
+mod archive;
+mod city_files;
 mod config;
 mod db;
 mod engine;
+mod geofence;
+mod geometry;
 mod lists;
+mod metrics;
+mod migrations;
+mod profile;
+mod pvp;
+mod retention;
+mod stats;
+mod storage;
+mod upsert;
 
 async fn parse(bytes: Vec<u8>) -> Result<(), ()> {
     let body = String::from_utf8(bytes).map_err(|e| error!("encoding error: {}", e))?;
@@ -28,14 +43,49 @@ async fn parse(bytes: Vec<u8>) -> Result<(), ()> {
 
     engine::submit(configs.into_iter().flat_map(|v| {
         debug!("incoming webhook: {}", v);
+        // rocketmap-entities only knows great and ultra leagues, read the rankings from the raw webhook
+        let pvp = pvp::from_webhook(&v);
         // this is a bit of a waste of memory, but there is no other way around
-        serde_json::from_value(v.clone()).map_err(|e| error!("deserialize error: {}\n{}", e, v))
+        serde_json::from_value(v.clone())
+            .map(|request| (request, pvp))
+            .map_err(|e| error!("deserialize error: {}\n{}", e, v))
     }))
     .await;
     Ok(())
 }
 
+async fn schema(migrate: bool) -> Result<(), ()> {
+    if migrate {
+        migrations::migrate().await
+    } else {
+        migrations::check().await
+    }
+}
+
+// with `cities_optional` an unreachable database doesn't stop the startup, its schema is checked once it answers
+async fn deferred_schema(migrate: bool) {
+    let period = Duration::from_secs(60);
+    let mut interval = interval_at(Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        if migrations::reachable().await {
+            if schema(migrate).await.is_err() {
+                error!("database schema doesn't fit, stopping");
+                process::exit(1);
+            }
+            info!("database reachable, schema checked");
+            return;
+        }
+    }
+}
+
 async fn service(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+    if let Some(admin) = config::CONFIG.admin.as_ref() {
+        if req.uri().path().trim_start_matches('/').starts_with("admin/") {
+            return Ok(admin::handle(req, admin).await);
+        }
+    }
+
     if config::CONFIG.service.safeword.is_none()
         || Some(req.uri().path().trim_matches('/')) == config::CONFIG.service.safeword.as_deref()
     {
@@ -44,8 +94,8 @@ async fn service(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
             e
         })?;
 
-        //spawn an independent future to parse the stream
-        spawn(async move {
+        //spawn an independent future to parse the stream, shutdown waits for it like for the writes
+        engine::spawn(async move {
             parse(bytes).await.ok();
         });
     }
@@ -54,12 +104,56 @@ async fn service(req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
     Ok(Response::new(Body::empty()))
 }
 
+// resolves on ctrl-c or, on unix, SIGTERM
+async fn shutdown() {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                error!("SIGTERM handler error: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+    info!("shutting down");
+}
+
 /// Launch service according to config
 #[tokio::main]
 async fn main() -> Result<(), ()> {
     tracing_subscriber::fmt::init();
 
-    lists::init().await;
+    // `hookedmap [config] migrate` only upgrades the database schema
+    if env::args().skip(1).any(|arg| arg == "migrate") {
+        return migrations::migrate().await;
+    }
+
+    let sqlite = db::storage().dialect() == upsert::Dialect::Sqlite;
+    let migrate = config::CONFIG.database.auto_migrate.unwrap_or(sqlite);
+    if config::CONFIG.service.cities_optional && !migrations::reachable().await {
+        warn!("database unreachable, starting anyway and checking its schema every minute");
+        tokio::spawn(deferred_schema(migrate));
+    } else {
+        schema(migrate).await?;
+    }
+
+    lists::init().await?;
+
+    retention::init();
+
+    metrics::init();
+
+    stats::init();
 
     //retrieve address and port, defaulting if not configured
     let addr = format!(
@@ -78,11 +172,17 @@ async fn main() -> Result<(), ()> {
     // bind and serve...
     Server::bind(&addr)
         .serve(service)
+        .with_graceful_shutdown(shutdown())
         .await
         .map_err(|e| {
             error!("server error: {}", e);
         })
         .ok();
 
+    // requests still being written count too
+    engine::drain().await;
+    // counters aggregated in memory would be lost otherwise
+    stats::flush().await;
+
     Ok(())
 }
@@ -0,0 +1,124 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+
+use tracing::info;
+
+/// Prepared statement cache usage, across every connection
+///
+/// SQLite doesn't report it, rusqlite doesn't expose its cache.
+pub static STATEMENTS: Lazy<Statements> = Lazy::new(Statements::default);
+
+/// Entities found outside the ingest geofence
+pub static GEOFENCE: Lazy<Geofence> = Lazy::new(Geofence::default);
+
+/// Writes lost by the mirrors
+pub static MIRRORS: Lazy<Mirrors> = Lazy::new(Mirrors::default);
+
+/// Pokemon counts refused because their day may already be compacted
+pub static LATE_STATS: Lazy<LateStats> = Lazy::new(LateStats::default);
+
+#[derive(Default)]
+pub struct Statements {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Statements {
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn report(&self) {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        if hits == 0 && misses == 0 {
+            return;
+        }
+        let total = hits + misses;
+        info!("statement cache: {} hits, {} misses, {:.1}% hit rate", hits, misses, hits as f64 * 100.0 / total as f64);
+    }
+}
+
+#[derive(Default)]
+pub struct Geofence {
+    dropped: AtomicU64,
+    logged: AtomicU64,
+}
+
+impl Geofence {
+    pub fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn logged(&self) {
+        self.logged.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn report(&self) {
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        let logged = self.logged.load(Ordering::Relaxed);
+        if dropped > 0 || logged > 0 {
+            info!("geofence: {} entities dropped, {} outside but written", dropped, logged);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Mirrors {
+    failed: AtomicU64,
+}
+
+impl Mirrors {
+    pub fn failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn report(&self) {
+        let failed = self.failed.load(Ordering::Relaxed);
+        if failed > 0 {
+            info!("mirrors: {} failed writes", failed);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct LateStats {
+    refused: AtomicU64,
+}
+
+impl LateStats {
+    pub fn refused(&self, count: u64) {
+        self.refused.fetch_add(count, Ordering::Relaxed);
+    }
+
+    fn report(&self) {
+        let refused = self.refused.load(Ordering::Relaxed);
+        if refused > 0 {
+            info!("stats: {} late pokemon counts refused", refused);
+        }
+    }
+}
+
+/// Periodically logs the collected metrics
+pub fn init() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(Duration::from_secs(300));
+        // the first tick completes immediately, there is nothing to report yet
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            STATEMENTS.report();
+            GEOFENCE.report();
+            MIRRORS.report();
+            LATE_STATS.report();
+        }
+    });
+}
@@ -0,0 +1,181 @@
+use chrono::Utc;
+
+use tracing::{error, info};
+
+use crate::{
+    config::{Profile, CONFIG},
+    db::storage,
+    profile::MAP_COLUMNS,
+    upsert::Dialect,
+};
+
+/// A schema change embedded in the binary, applied once and recorded into `schema_version`
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    sql: &'static str,
+    // map tables, only applied to rdm databases
+    entities: &'static str,
+}
+
+impl Migration {
+    /// Statements to run against a database with the given schema profile
+    pub fn sql(&self, profile: Profile) -> String {
+        match profile {
+            Profile::Rdm if !self.entities.is_empty() => format!("{}\n{}", self.entities, self.sql),
+            _ => self.sql.to_owned(),
+        }
+    }
+}
+
+macro_rules! migrations {
+    ($dialect:literal: $($version:literal => $name:literal $(+ $entities:literal)?),* $(,)?) => {
+        &[$(Migration {
+            version: $version,
+            name: $name,
+            sql: include_str!(concat!("../migrations/", $dialect, "/", $name, ".sql")),
+            entities: migrations!(@entities $dialect $($entities)?),
+        }),*]
+    };
+    (@entities $dialect:literal) => { "" };
+    (@entities $dialect:literal $entities:literal) => {
+        include_str!(concat!("../migrations/", $dialect, "/", $entities, ".sql"))
+    };
+}
+
+static MYSQL: &[Migration] = migrations!("mysql":
+    1 => "0001_initial" + "0001_entities",
+    2 => "0002_city_entity_stats",
+    3 => "0003_pokemon_stats_form_city",
+    4 => "0004_city_timezone",
+    5 => "0005_pokemon_stats_rollups",
+    6 => "0006_pokemon_expired",
+    7 => "0007_pokemon_pvp_primary_key",
+);
+static POSTGRES: &[Migration] = migrations!("postgres":
+    1 => "0001_initial" + "0001_entities",
+    2 => "0002_city_entity_stats",
+    3 => "0003_pokemon_stats_form_city",
+    4 => "0004_city_timezone",
+    5 => "0005_pokemon_stats_rollups",
+    6 => "0006_pokemon_expired",
+    7 => "0007_pokemon_pvp_primary_key",
+);
+static SQLITE: &[Migration] = migrations!("sqlite":
+    1 => "0001_initial" + "0001_entities",
+    2 => "0002_city_entity_stats",
+    3 => "0003_pokemon_stats_form_city",
+    4 => "0004_city_timezone",
+    5 => "0005_pokemon_stats_rollups",
+    6 => "0006_pokemon_expired" + "0006_entities",
+    7 => "0007_pokemon_pvp_primary_key",
+    8 => "0008_pokemon_expired_columns" + "0008_entities",
+);
+
+fn for_dialect(dialect: Dialect) -> &'static [Migration] {
+    match dialect {
+        Dialect::Mysql => MYSQL,
+        Dialect::Postgres => POSTGRES,
+        Dialect::Sqlite => SQLITE,
+    }
+}
+
+/// Latest schema version this binary knows about
+fn latest(dialect: Dialect) -> u32 {
+    for_dialect(dialect).iter().map(|m| m.version).max().unwrap_or_default()
+}
+
+// databases written before hookedmap managed the schema already hold the map tables
+async fn unmanaged_map() -> Result<bool, ()> {
+    let columns = storage().table_columns("pokemon").await.map_err(|e| error!("schema inspection error: {}", e))?;
+    Ok(!columns.is_empty())
+}
+
+/// Tells if the database answers, whatever its schema
+pub async fn reachable() -> bool {
+    storage().schema_version().await.is_ok()
+}
+
+/// Applies every pending migration, in order
+///
+/// An existing map database without `schema_version` is adopted: the first migration only creates the tables
+/// it lacks, then the later ones are applied as usual.
+pub async fn migrate() -> Result<(), ()> {
+    let dialect = storage().dialect();
+    let current = match storage().schema_version().await.map_err(|e| error!("schema version error: {}", e))? {
+        Some(current) => current,
+        None => {
+            let profile = CONFIG.database.profile;
+            if unmanaged_map().await? {
+                info!("adopting the existing map tables as schema version 1");
+            } else if profile != Profile::Rdm {
+                error!("map tables are missing, let the scanner of the {:?} profile create them first", profile);
+                return Err(());
+            }
+            0
+        }
+    };
+    if current > latest(dialect) {
+        error!(
+            "database schema version {} is newer than the supported {}, upgrade hookedmap",
+            current,
+            latest(dialect)
+        );
+        return Err(());
+    }
+
+    for migration in for_dialect(dialect).iter().filter(|m| m.version > current) {
+        info!("applying migration {}", migration.name);
+        storage()
+            .apply_migration(migration, Utc::now().timestamp())
+            .await
+            .map_err(|e| error!("migration {} error: {}", migration.name, e))?;
+    }
+
+    info!("database schema is at version {}", latest(dialect));
+    Ok(())
+}
+
+// map tables aren't created for every profile, the columns it maps to have to be there anyway
+async fn check_columns() -> Result<(), ()> {
+    let profile = CONFIG.database.profile;
+    let mut missing = Vec::new();
+    for (table, columns) in MAP_COLUMNS {
+        let found = storage().table_columns(table).await.map_err(|e| error!("schema inspection error: {}", e))?;
+        for name in profile.columns(table, columns) {
+            if !found.contains(&name) {
+                missing.push(format!("{}.{}", table, name));
+            }
+        }
+    }
+    if !missing.is_empty() {
+        error!("database doesn't match the {:?} profile, missing columns: {}", profile, missing.join(", "));
+        return Err(());
+    }
+    Ok(())
+}
+
+/// Refuses to go on when the database schema doesn't match the one this binary was built for
+pub async fn check() -> Result<(), ()> {
+    let dialect = storage().dialect();
+    let latest = latest(dialect);
+    match storage().schema_version().await.map_err(|e| error!("schema version error: {}", e))? {
+        // existing map tables are only adopted by `migrate` or with `auto_migrate`
+        None => {
+            error!("database schema isn't managed by hookedmap, run `hookedmap <config> migrate` first");
+            Err(())
+        }
+        Some(current) if current < latest => {
+            error!(
+                "database schema version {} is older than the required {}, run `hookedmap <config> migrate` first",
+                current, latest
+            );
+            Err(())
+        }
+        Some(current) if current > latest => {
+            error!("database schema version {} is newer than the supported {}, upgrade hookedmap", current, latest);
+            Err(())
+        }
+        Some(_) => check_columns().await,
+    }
+}
@@ -0,0 +1,216 @@
+use crate::{config::Profile, upsert::Value};
+
+/// Where a column of the rdm schema ends up in the target schema
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field<'a> {
+    /// the target schema has no such column, the value is dropped
+    Missing,
+    /// plain (maybe renamed) column
+    Column(&'a str),
+    /// column storing unix timestamps as datetimes
+    Datetime(&'a str),
+}
+
+use Field::{Column, Datetime, Missing};
+
+/// Columns of the map tables hookedmap writes, by their rdm name
+pub const MAP_COLUMNS: &[(&str, &[&str])] = &[
+    (
+        "gym",
+        &[
+            "id",
+            "updated",
+            "first_seen_timestamp",
+            "lat",
+            "lon",
+            "name",
+            "url",
+            "last_modified_timestamp",
+            "enabled",
+            "team_id",
+            "guarding_pokemon_id",
+            "availble_slots",
+            "in_battle",
+            "sponsor_id",
+            "ar_scan_eligible",
+            "ex_raid_eligible",
+            "raid_spawn_timestamp",
+            "raid_battle_timestamp",
+            "raid_end_timestamp",
+            "raid_level",
+            "raid_pokemon_id",
+            "raid_pokemon_cp",
+            "raid_pokemon_move_1",
+            "raid_pokemon_move_2",
+            "raid_pokemon_form",
+            "raid_is_exclusive",
+            "raid_pokemon_gender",
+            "raid_pokemon_evolution",
+        ],
+    ),
+    (
+        "pokestop",
+        &[
+            "id",
+            "updated",
+            "first_seen_timestamp",
+            "lat",
+            "lon",
+            "name",
+            "url",
+            "enabled",
+            "last_modified_timestamp",
+            "lure_expire_timestamp",
+            "lure_id",
+            "pokestop_display",
+            "incident_expire_timestamp",
+            "grunt_type",
+            "ar_scan_eligible",
+            "quest_type",
+            "quest_target",
+            "quest_template",
+            "quest_rewards",
+            "quest_conditions",
+            "quest_timestamp",
+            "alternative_quest_type",
+            "alternative_quest_target",
+            "alternative_quest_template",
+            "alternative_quest_rewards",
+            "alternative_quest_conditions",
+            "alternative_quest_timestamp",
+        ],
+    ),
+    (
+        "pokemon",
+        &[
+            "id",
+            "updated",
+            "first_seen_timestamp",
+            "pokemon_id",
+            "pokestop_id",
+            "spawn_id",
+            "lat",
+            "lon",
+            "expire_timestamp",
+            "expire_timestamp_verified",
+            "gender",
+            "cp",
+            "form",
+            "costume",
+            "atk_iv",
+            "def_iv",
+            "sta_iv",
+            "move_1",
+            "move_2",
+            "weight",
+            "size",
+            "capture_1",
+            "capture_2",
+            "capture_3",
+            "weather",
+            "level",
+            "cell_id",
+            "username",
+            "shiny",
+            "display_pokemon_id",
+            "is_event",
+            "pvp_rankings_great_league",
+            "pvp_rankings_ultra_league",
+        ],
+    ),
+    ("spawnpoint", &["id", "lat", "lon", "updated", "last_seen", "despawn_sec"]),
+];
+
+impl Profile {
+    /// Physical name of a column, engine and merge config always talk about rdm names
+    pub fn field<'a>(self, table: &str, column: &'a str) -> Field<'a> {
+        match self {
+            Profile::Rdm => Column(column),
+            Profile::Golbat => golbat(table, column),
+            Profile::Rocketmap => rocketmap(table, column),
+        }
+    }
+
+    /// Physical column holding a timestamp, with `timestamp` converted to be compared with it
+    pub fn timestamp(self, table: &str, column: &str, timestamp: i64) -> Option<(String, Value)> {
+        match self.field(table, column) {
+            Missing => None,
+            Column(name) => Some((name.to_owned(), timestamp.into())),
+            Datetime(name) => Some((name.to_owned(), Value::from(timestamp).into_datetime())),
+        }
+    }
+
+    /// Physical names of the given columns, skipping the ones the target schema lacks
+    pub fn columns(self, table: &str, columns: &[&str]) -> Vec<String> {
+        columns
+            .iter()
+            .filter_map(|column| match self.field(table, column) {
+                Missing => None,
+                Column(name) | Datetime(name) => Some(name.to_owned()),
+            })
+            .collect()
+    }
+}
+
+fn golbat<'a>(table: &str, column: &'a str) -> Field<'a> {
+    match (table, column) {
+        ("gym", "availble_slots") => Column("available_slots"),
+        // golbat uses `size` for the XXS/XXL class
+        ("pokemon", "size") => Column("height"),
+        // rankings are stored into a single `pvp` column, pokemon_pvp has them all anyway
+        ("pokemon", "pvp_rankings_great_league" | "pvp_rankings_ultra_league") => Missing,
+        // incidents live in their own table
+        ("pokestop", "pokestop_display" | "incident_expire_timestamp" | "grunt_type") => Missing,
+        _ => Column(column),
+    }
+}
+
+// table names match, but raids, gym details and quests live in separate tables on RocketMap and aren't written
+fn rocketmap<'a>(table: &str, column: &'a str) -> Field<'a> {
+    match (table, column) {
+        ("gym", "id") => Column("gym_id"),
+        ("gym", "lat") => Column("latitude"),
+        ("gym", "lon") => Column("longitude"),
+        ("gym", "guarding_pokemon_id") => Column("guard_pokemon_id"),
+        ("gym", "availble_slots") => Column("slots_available"),
+        ("gym", "in_battle") => Column("is_in_battle"),
+        ("gym", "ex_raid_eligible") => Column("is_ex_raid_eligible"),
+        ("gym", "last_modified_timestamp") => Datetime("last_modified"),
+        ("gym", "updated") => Datetime("last_scanned"),
+        ("gym", "team_id" | "enabled") => Column(column),
+        ("pokestop", "id") => Column("pokestop_id"),
+        ("pokestop", "lat") => Column("latitude"),
+        ("pokestop", "lon") => Column("longitude"),
+        ("pokestop", "url") => Column("image"),
+        ("pokestop", "last_modified_timestamp") => Datetime("last_modified"),
+        ("pokestop", "lure_expire_timestamp") => Datetime("lure_expiration"),
+        ("pokestop", "lure_id") => Column("active_fort_modifier"),
+        ("pokestop", "updated") => Datetime("last_updated"),
+        ("pokestop", "incident_expire_timestamp") => Datetime("incident_expiration"),
+        ("pokestop", "grunt_type") => Column("incident_grunt_type"),
+        ("pokestop", "name" | "enabled") => Column(column),
+        ("pokemon", "id") => Column("encounter_id"),
+        ("pokemon", "spawn_id") => Column("spawnpoint_id"),
+        ("pokemon", "lat") => Column("latitude"),
+        ("pokemon", "lon") => Column("longitude"),
+        ("pokemon", "expire_timestamp") => Datetime("disappear_time"),
+        ("pokemon", "updated") => Datetime("last_modified"),
+        ("pokemon", "atk_iv") => Column("individual_attack"),
+        ("pokemon", "def_iv") => Column("individual_defense"),
+        ("pokemon", "sta_iv") => Column("individual_stamina"),
+        ("pokemon", "size") => Column("height"),
+        ("pokemon", "capture_1") => Column("catch_prob_1"),
+        ("pokemon", "capture_2") => Column("catch_prob_2"),
+        ("pokemon", "capture_3") => Column("catch_prob_3"),
+        ("pokemon", "weather") => Column("weather_boosted_condition"),
+        ("pokemon", "pokemon_id" | "move_1" | "move_2" | "cp" | "gender" | "form" | "costume" | "weight") => {
+            Column(column)
+        }
+        ("spawnpoint", "lat") => Column("latitude"),
+        ("spawnpoint", "lon") => Column("longitude"),
+        ("spawnpoint", "updated") => Datetime("last_scanned"),
+        ("spawnpoint", "id") => Column(column),
+        ("gym" | "pokestop" | "pokemon" | "spawnpoint", _) => Missing,
+        _ => Column(column),
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use serde_json::value::Value;
+
+use tracing::{error, warn};
+
+/// length of `pokemon_pvp.league`
+const LEAGUE_LENGTH: usize = 16;
+
+/// PvP rankings of a single encounter, grouped by league name
+pub type Leagues = BTreeMap<String, Vec<Ranking>>;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Ranking {
+    pub pokemon: u16,
+    pub form: Option<u16>,
+    pub rank: Option<u32>,
+    pub cp: Option<u32>,
+    pub level: Option<f64>,
+    pub percentage: Option<f64>,
+}
+
+/// Extracts every league from a raw pokemon webhook.
+///
+/// Both the legacy `pvp_rankings_<league>_league` fields and the newer `pvp` map are supported,
+/// this way leagues unknown to rocketmap-entities aren't lost.
+/// League names that wouldn't fit the `pokemon_pvp` table are skipped.
+pub fn from_webhook(webhook: &Value) -> Leagues {
+    let mut leagues = Leagues::new();
+    if webhook.get("type").and_then(Value::as_str) != Some("pokemon") {
+        return leagues;
+    }
+    let Some(message) = webhook.get("message").and_then(Value::as_object) else {
+        return leagues;
+    };
+
+    for (key, value) in message {
+        if let Some(league) = key.strip_prefix("pvp_rankings_").and_then(|s| s.strip_suffix("_league")) {
+            insert(&mut leagues, league, value);
+        }
+    }
+
+    if let Some(pvp) = message.get("pvp").and_then(Value::as_object) {
+        for (league, value) in pvp {
+            insert(&mut leagues, league, value);
+        }
+    }
+
+    leagues
+}
+
+fn insert(leagues: &mut Leagues, league: &str, value: &Value) {
+    if value.is_null() {
+        return;
+    }
+    if !valid_league(league) {
+        warn!("invalid league name {:?}, skipping its rankings", league);
+        return;
+    }
+    match Vec::<Ranking>::deserialize(value) {
+        Ok(rankings) if !rankings.is_empty() => {
+            leagues.insert(league.to_owned(), best(rankings));
+        }
+        Ok(_) => {}
+        Err(e) => error!("deserialize error: {} league rankings {}\n{}", league, e, value),
+    }
+}
+
+fn valid_league(league: &str) -> bool {
+    !league.is_empty()
+        && league.len() <= LEAGUE_LENGTH
+        && league.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+}
+
+// a pokemon is ranked once per level cap, only its best rank is kept
+fn best(mut rankings: Vec<Ranking>) -> Vec<Ranking> {
+    rankings.sort_by_key(|r| (r.pokemon, r.form.unwrap_or_default(), r.rank.unwrap_or(u32::MAX)));
+    rankings.dedup_by_key(|r| (r.pokemon, r.form.unwrap_or_default()));
+    rankings
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn ranks(leagues: &Leagues, league: &str) -> Vec<(u16, Option<u16>, Option<u32>)> {
+        leagues[league].iter().map(|r| (r.pokemon, r.form, r.rank)).collect()
+    }
+
+    #[test]
+    fn legacy_fields() {
+        let leagues = from_webhook(&json!({"type": "pokemon", "message": {
+            "pvp_rankings_great_league": [{"pokemon": 1, "rank": 3, "cp": 1490, "level": 30.5, "percentage": 0.98}],
+            "pvp_rankings_ultra_league": [{"pokemon": 2, "form": 5, "rank": 10}],
+            "pvp_rankings_master_league": null,
+            "pvp_rankings_little_league": [],
+        }}));
+        assert_eq!(leagues.keys().collect::<Vec<_>>(), ["great", "ultra"]);
+        assert_eq!(ranks(&leagues, "great"), [(1, None, Some(3))]);
+        assert_eq!(ranks(&leagues, "ultra"), [(2, Some(5), Some(10))]);
+        assert_eq!(leagues["great"][0].cp, Some(1490));
+    }
+
+    #[test]
+    fn pvp_map() {
+        let leagues = from_webhook(&json!({"type": "pokemon", "message": {
+            "pvp": {
+                "little": [{"pokemon": 1, "rank": 1}],
+                "great": [{"pokemon": 2, "rank": 4}, {"pokemon": 3, "form": 7, "rank": 2}],
+            },
+        }}));
+        assert_eq!(leagues.keys().collect::<Vec<_>>(), ["great", "little"]);
+        assert_eq!(ranks(&leagues, "great"), [(2, None, Some(4)), (3, Some(7), Some(2))]);
+        assert_eq!(ranks(&leagues, "little"), [(1, None, Some(1))]);
+    }
+
+    #[test]
+    fn pvp_map_wins_over_legacy_fields() {
+        let leagues = from_webhook(&json!({"type": "pokemon", "message": {
+            "pvp_rankings_great_league": [{"pokemon": 1, "rank": 30}],
+            "pvp": {"great": [{"pokemon": 1, "rank": 3}]},
+        }}));
+        assert_eq!(ranks(&leagues, "great"), [(1, None, Some(3))]);
+    }
+
+    #[test]
+    fn best_rank_per_pokemon() {
+        // one ranking per level cap
+        let leagues = from_webhook(&json!({"type": "pokemon", "message": {"pvp": {"great": [
+            {"pokemon": 1, "rank": 40, "cap": 40},
+            {"pokemon": 1, "rank": 2, "cap": 50},
+            {"pokemon": 1, "form": 0, "rank": 5, "cap": 51},
+            {"pokemon": 2, "rank": null},
+        ]}}}));
+        assert_eq!(ranks(&leagues, "great"), [(1, None, Some(2)), (2, None, None)]);
+    }
+
+    #[test]
+    fn ignored_webhooks() {
+        assert!(from_webhook(&json!({"type": "raid", "message": {"pvp": {"great": [{"pokemon": 1}]}}})).is_empty());
+        assert!(from_webhook(&json!({"type": "pokemon"})).is_empty());
+        // malformed leagues are skipped, the others are kept
+        let leagues = from_webhook(&json!({"type": "pokemon", "message": {
+            "pvp": {"great": [{"rank": 1}], "ultra": [{"pokemon": 1}]},
+        }}));
+        assert_eq!(leagues.keys().collect::<Vec<_>>(), ["ultra"]);
+    }
+
+    #[test]
+    fn invalid_league_names() {
+        let leagues = from_webhook(&json!({"type": "pokemon", "message": {
+            "pvp_rankings__league": [{"pokemon": 1, "rank": 1}],
+            "pvp": {
+                "great": [{"pokemon": 1, "rank": 1}],
+                "a_league_name_too_long": [{"pokemon": 1, "rank": 1}],
+                "Great League": [{"pokemon": 1, "rank": 1}],
+                "little_2024": [{"pokemon": 1, "rank": 1}],
+            },
+        }}));
+        assert_eq!(leagues.keys().collect::<Vec<_>>(), ["great", "little_2024"]);
+    }
+}
@@ -0,0 +1,125 @@
+use std::{future::Future, time::Duration};
+
+use chrono::Utc;
+
+use chrono_tz::Tz;
+
+use tokio::time::{interval_at, sleep, Instant};
+
+use tracing::{error, info};
+
+use crate::{
+    config::{Retention, CONFIG},
+    db::storage,
+    storage::Error,
+};
+
+const LURE: &[&str] = &["lure_expire_timestamp", "lure_id"];
+const INCIDENT: &[&str] = &["incident_expire_timestamp", "pokestop_display", "grunt_type"];
+const QUEST: &[&str] =
+    &["quest_timestamp", "quest_type", "quest_target", "quest_template", "quest_rewards", "quest_conditions"];
+const ALTERNATIVE_QUEST: &[&str] = &[
+    "alternative_quest_timestamp",
+    "alternative_quest_type",
+    "alternative_quest_target",
+    "alternative_quest_template",
+    "alternative_quest_rewards",
+    "alternative_quest_conditions",
+];
+
+/// Starts the cleanup task, if configured
+pub fn init() {
+    let Some(retention) = CONFIG.retention.as_ref() else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        let period = Duration::from_secs(retention.interval.unwrap_or(300));
+        let mut interval = interval_at(Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            cleanup(retention).await;
+        }
+    });
+}
+
+async fn cleanup(retention: &Retention) {
+    let now = Utc::now().timestamp();
+
+    if let Some(grace) = retention.pokemon {
+        let archive = retention.keep_expired_pokemon.then_some("pokemon_expired");
+        batches(retention, "expired pokemon", |limit| {
+            storage().delete_expired("pokemon", "expire_timestamp", now - grace as i64, limit, archive)
+        })
+        .await;
+    }
+
+    if retention.clear_expired.unwrap_or(true) {
+        batches(retention, "expired lures", |limit| {
+            storage().clear_expired("pokestop", "lure_expire_timestamp", LURE, now, limit)
+        })
+        .await;
+        batches(retention, "expired incidents", |limit| {
+            storage().clear_expired("pokestop", "incident_expire_timestamp", INCIDENT, now, limit)
+        })
+        .await;
+
+        // quests last until midnight, in the timezone days are counted in, the server one is unrelated
+        let timezone = CONFIG.stats.as_ref().and_then(|stats| stats.timezone).unwrap_or(Tz::UTC);
+        let midnight = Utc::now()
+            .with_timezone(&timezone)
+            .date_naive()
+            .and_hms_opt(0, 0, 0)
+            .and_then(|midnight| midnight.and_local_timezone(timezone).earliest())
+            .map(|midnight| midnight.timestamp());
+        if let Some(midnight) = midnight {
+            batches(retention, "expired quests", |limit| {
+                storage().clear_expired("pokestop", "quest_timestamp", QUEST, midnight, limit)
+            })
+            .await;
+            batches(retention, "expired alternative quests", |limit| {
+                storage().clear_expired("pokestop", "alternative_quest_timestamp", ALTERNATIVE_QUEST, midnight, limit)
+            })
+            .await;
+        }
+    }
+
+    if let Some(days) = retention.stale_days {
+        let before = now - days as i64 * 86400;
+        batches(retention, "stale gyms", |limit| storage().delete_expired("gym", "updated", before, limit, None)).await;
+        batches(retention, "stale pokestops", |limit| {
+            storage().delete_expired("pokestop", "updated", before, limit, None)
+        })
+        .await;
+    }
+}
+
+// runs small statements until there is nothing left, pausing between them to not hold locks for long
+async fn batches<F, Fut>(retention: &Retention, what: &str, f: F)
+where
+    F: Fn(u64) -> Fut,
+    Fut: Future<Output = Result<u64, Error>>,
+{
+    let limit = retention.batch_size.unwrap_or(1000);
+    let pause = Duration::from_millis(retention.batch_pause.unwrap_or(100));
+    let mut total = 0;
+    loop {
+        match f(limit).await {
+            Ok(affected) => {
+                total += affected;
+                if affected < limit {
+                    break;
+                }
+            }
+            Err(e) => {
+                error!("cleanup {} error: {}", what, e);
+                break;
+            }
+        }
+        sleep(pause).await;
+    }
+
+    if total > 0 {
+        info!("cleanup: {} {}", total, what);
+    }
+}
@@ -0,0 +1,236 @@
+use std::{collections::HashMap, mem, sync::Mutex as SyncMutex, time::Duration};
+
+use chrono::{DateTime, Days, NaiveDate, Utc};
+
+use chrono_tz::Tz;
+
+use once_cell::sync::Lazy;
+
+use tokio::time::{interval, interval_at, Instant};
+
+use tracing::{debug, error, info};
+
+use crate::{
+    config::{Stats, CONFIG},
+    db::storage,
+    lists::CITIES,
+    metrics::LATE_STATS,
+    storage::{Period, PokemonStat},
+};
+
+// rows written by a single statement
+const CHUNK: usize = 1000;
+
+// days newer than this can still receive counts, from cities far from UTC or not yet flushed
+const COMPACTION_LAG: u64 = 2;
+
+// daily counts older than this are refused, one day before compaction can pick them up
+const LATE_LAG: u64 = COMPACTION_LAG - 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Key {
+    period: Period,
+    pokemon_id: u16,
+    form: u16,
+    city_id: u16,
+}
+
+// counts not yet written into `pokemon_stats` and `pokemon_stats_hourly`
+static POKEMON: Lazy<SyncMutex<HashMap<Key, u32>>> = Lazy::new(Default::default);
+
+fn add(key: Key, count: u32) {
+    if let Ok(mut counts) = POKEMON.lock() {
+        let total = counts.entry(key).or_default();
+        *total = total.saturating_add(count);
+    }
+}
+
+/// The day a moment falls in for the stats of a city, in its timezone or the configured one
+pub fn day(city_id: Option<u16>, at: DateTime<Utc>) -> NaiveDate {
+    let city = city_id.and_then(|id| CITIES.load().cities.get(&id).and_then(|city| city.timezone));
+    local_day(city, CONFIG.stats.as_ref().and_then(|stats| stats.timezone), at)
+}
+
+// the city timezone wins over the configured one, UTC is the last resort
+fn local_day(city: Option<Tz>, configured: Option<Tz>, at: DateTime<Utc>) -> NaiveDate {
+    at.with_timezone(&city.or(configured).unwrap_or(Tz::UTC)).date_naive()
+}
+
+/// Counts a pokemon into the given day and the hour of `at`, it's written on the next flush
+pub fn count_pokemon(day: NaiveDate, at: DateTime<Utc>, pokemon_id: u16, form: u16, city_id: Option<u16>) {
+    let timestamp = at.timestamp();
+    let hour = timestamp - timestamp.rem_euclid(3600);
+    for period in [Period::Day(day), Period::Hour(hour)] {
+        add(Key { period, pokemon_id, form, city_id: city_id.unwrap_or_default() }, 1);
+    }
+}
+
+// writes stats sharing the same kind of period, failed ones are kept for the next flush
+async fn write(stats: &[PokemonStat]) {
+    for chunk in stats.chunks(CHUNK) {
+        if let Err(e) = storage().add_pokemon_stats(chunk).await {
+            error!("pokemon stats flush error: {}", e);
+            for stat in chunk {
+                add(
+                    Key { period: stat.period, pokemon_id: stat.pokemon_id, form: stat.form, city_id: stat.city_id },
+                    stat.count,
+                );
+            }
+        }
+    }
+}
+
+/// Writes the pending counts, failed ones are kept for the next flush
+///
+/// Daily counts of days compaction may have picked up are refused and reported by the metrics instead.
+pub async fn flush() {
+    let counts = POKEMON.lock().map(|mut counts| mem::take(&mut *counts)).unwrap_or_default();
+    if counts.is_empty() {
+        return;
+    }
+
+    let (hourly, daily): (Vec<PokemonStat>, Vec<PokemonStat>) = counts
+        .into_iter()
+        .map(|(key, count)| PokemonStat {
+            period: key.period,
+            pokemon_id: key.pokemon_id,
+            form: key.form,
+            city_id: key.city_id,
+            count,
+        })
+        .partition(|stat| matches!(stat.period, Period::Hour(_)));
+    // a day may already be in the weekly and monthly tables, adding to it would never reach them
+    let oldest = Utc::now().date_naive() - Days::new(LATE_LAG);
+    let (daily, late): (Vec<PokemonStat>, Vec<PokemonStat>) =
+        daily.into_iter().partition(|stat| matches!(stat.period, Period::Day(day) if day >= oldest));
+    if !late.is_empty() {
+        LATE_STATS.refused(late.iter().map(|stat| u64::from(stat.count)).sum());
+    }
+    write(&hourly).await;
+    write(&daily).await;
+    debug!("flushed {} hourly and {} daily pokemon stats rows", hourly.len(), daily.len());
+}
+
+// rolls finished days into weeks and months, then drops the rows past their retention
+async fn compact(stats: Option<&Stats>) {
+    let today = Utc::now().date_naive();
+    let before = today - Days::new(COMPACTION_LAG);
+    // a lagging replica would miss days, the primary decides what is compacted
+    let days = match storage().uncompacted_stats_days(before).await {
+        Ok(days) => days,
+        Err(e) => {
+            error!("stats compaction error: {}", e);
+            return;
+        }
+    };
+    for day in days {
+        match storage().compact_stats_day(day).await {
+            Ok(true) => info!("compacted pokemon stats of {}", day),
+            Ok(false) => {}
+            Err(e) => {
+                error!("stats compaction of {} error: {}", day, e);
+                return;
+            }
+        }
+    }
+
+    if let Some(days) = stats.and_then(|stats| stats.daily_days) {
+        match storage().prune_stats_days(today - Days::new(days)).await {
+            Ok(0) => {}
+            Ok(deleted) => info!("deleted {} compacted daily pokemon stats", deleted),
+            Err(e) => error!("daily pokemon stats cleanup error: {}", e),
+        }
+    }
+
+    if let Some(days) = stats.and_then(|stats| stats.city_days) {
+        match storage().prune_city_stats_days(today - Days::new(days)).await {
+            Ok(0) => {}
+            Ok(deleted) => info!("deleted {} city stats", deleted),
+            Err(e) => error!("city stats cleanup error: {}", e),
+        }
+    }
+
+    if let Some(days) = stats.and_then(|stats| stats.hourly_days) {
+        let before = Utc::now().timestamp() - days as i64 * 86400;
+        loop {
+            match storage().delete_expired("pokemon_stats_hourly", "hour", before, CHUNK as u64, None).await {
+                Ok(deleted) if deleted > 0 => info!("deleted {} hourly pokemon stats", deleted),
+                Ok(_) => break,
+                Err(e) => {
+                    error!("hourly pokemon stats cleanup error: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Starts the periodic flush and compaction
+pub fn init() {
+    let stats = CONFIG.stats.as_ref();
+
+    let period = Duration::from_secs(stats.and_then(|stats| stats.flush_interval).unwrap_or(60));
+    tokio::spawn(async move {
+        let mut interval = interval_at(Instant::now() + period, period);
+        loop {
+            interval.tick().await;
+            flush().await;
+        }
+    });
+
+    let period = stats.and_then(|stats| stats.compaction_interval).unwrap_or(3600);
+    if period > 0 {
+        tokio::spawn(async move {
+            // the first run happens right away, to catch up with the days missed while down
+            let mut interval = interval(Duration::from_secs(period));
+            loop {
+                interval.tick().await;
+                compact(stats).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, TimeZone, Utc};
+
+    use chrono_tz::Tz;
+
+    use super::local_day;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn utc_midnight() {
+        let before = Utc.with_ymd_and_hms(2024, 3, 9, 23, 50, 0).unwrap();
+        let after = Utc.with_ymd_and_hms(2024, 3, 10, 0, 20, 0).unwrap();
+        assert_eq!(local_day(None, None, before), date(2024, 3, 9));
+        assert_eq!(local_day(None, None, after), date(2024, 3, 10));
+    }
+
+    #[test]
+    fn configured_timezone() {
+        // both moments are on the 9th in UTC, Rome is an hour ahead and already on the 10th for the second
+        let before = Utc.with_ymd_and_hms(2024, 3, 9, 22, 50, 0).unwrap();
+        let after = Utc.with_ymd_and_hms(2024, 3, 9, 23, 20, 0).unwrap();
+        assert_eq!(local_day(None, Some(Tz::Europe__Rome), before), date(2024, 3, 9));
+        assert_eq!(local_day(None, Some(Tz::Europe__Rome), after), date(2024, 3, 10));
+        assert_eq!(local_day(None, None, after), date(2024, 3, 9));
+    }
+
+    #[test]
+    fn city_timezone_wins() {
+        let at = Utc.with_ymd_and_hms(2024, 3, 10, 8, 0, 0).unwrap();
+        // Honolulu is still on the previous day, Rome already on the same one as UTC
+        assert_eq!(local_day(Some(Tz::Pacific__Honolulu), Some(Tz::Europe__Rome), at), date(2024, 3, 9));
+        assert_eq!(local_day(None, Some(Tz::Europe__Rome), at), date(2024, 3, 10));
+        // across the city midnight
+        let before = Utc.with_ymd_and_hms(2024, 3, 10, 9, 50, 0).unwrap();
+        let after = Utc.with_ymd_and_hms(2024, 3, 10, 10, 20, 0).unwrap();
+        assert_eq!(local_day(Some(Tz::Pacific__Honolulu), None, before), date(2024, 3, 9));
+        assert_eq!(local_day(Some(Tz::Pacific__Honolulu), None, after), date(2024, 3, 10));
+    }
+}
@@ -0,0 +1,186 @@
+use std::{future::Future, sync::Arc};
+
+use chrono::NaiveDate;
+
+use futures_util::future::BoxFuture;
+
+use tracing::error;
+
+use super::{CityStats, Error, PokemonStat, Storage};
+
+use crate::{
+    lists::City,
+    metrics::MIRRORS,
+    migrations::Migration,
+    pvp::Leagues,
+    upsert::{Dialect, Upsert},
+};
+
+/// Sends every write to the main storage and, in background, to its mirrors.
+///
+/// Mirrors are best-effort: they never slow down nor fail the main writes, a failed write is logged, counted
+/// in the metrics and never retried, so a mirror can miss rows the main storage has.
+/// Reads and migrations only involve the main storage.
+pub struct Mirrored {
+    primary: Box<dyn Storage>,
+    mirrors: Vec<Arc<dyn Storage>>,
+}
+
+impl Mirrored {
+    pub fn new(primary: Box<dyn Storage>, mirrors: Vec<Arc<dyn Storage>>) -> Self {
+        Mirrored { primary, mirrors }
+    }
+
+    fn mirror<F, Fut, T>(&self, what: &'static str, f: F)
+    where
+        F: Fn(Arc<dyn Storage>) -> Fut,
+        Fut: Future<Output = Result<T, Error>> + Send + 'static,
+    {
+        for (index, mirror) in self.mirrors.iter().enumerate() {
+            let fut = f(Arc::clone(mirror));
+            tokio::spawn(async move {
+                if let Err(e) = fut.await {
+                    MIRRORS.failed();
+                    error!("mirror #{} {} error: {}", index + 1, what, e);
+                }
+            });
+        }
+    }
+}
+
+impl Storage for Mirrored {
+    fn dialect(&self) -> Dialect {
+        self.primary.dialect()
+    }
+
+    fn schema_version(&self) -> BoxFuture<'_, Result<Option<u32>, Error>> {
+        self.primary.schema_version()
+    }
+
+    fn table_columns<'a>(&'a self, table: &'a str) -> BoxFuture<'a, Result<Vec<String>, Error>> {
+        self.primary.table_columns(table)
+    }
+
+    fn apply_migration(&self, migration: &'static Migration, applied_at: i64) -> BoxFuture<'_, Result<(), Error>> {
+        self.primary.apply_migration(migration, applied_at)
+    }
+
+    fn upsert(&self, upsert: Upsert) -> BoxFuture<'_, Result<u64, Error>> {
+        self.mirror("upsert", |mirror| {
+            let upsert = upsert.clone();
+            async move { mirror.upsert(upsert).await }
+        });
+        self.primary.upsert(upsert)
+    }
+
+    fn replace_pokemon_pvp<'a>(&'a self, encounter_id: &'a str, pvp: &'a Leagues) -> BoxFuture<'a, Result<(), Error>> {
+        self.mirror("replace_pokemon_pvp", |mirror| {
+            let (encounter_id, pvp) = (encounter_id.to_owned(), pvp.clone());
+            async move { mirror.replace_pokemon_pvp(&encounter_id, &pvp).await }
+        });
+        self.primary.replace_pokemon_pvp(encounter_id, pvp)
+    }
+
+    fn create_partition<'a>(&'a self, table: &'a str, partition: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        self.mirror("create_partition", |mirror| {
+            let (table, partition) = (table.to_owned(), partition.to_owned());
+            async move { mirror.create_partition(&table, &partition).await }
+        });
+        self.primary.create_partition(table, partition)
+    }
+
+    fn add_pokemon_stats<'a>(&'a self, stats: &'a [PokemonStat]) -> BoxFuture<'a, Result<(), Error>> {
+        self.mirror("add_pokemon_stats", |mirror| {
+            let stats = stats.to_vec();
+            async move { mirror.add_pokemon_stats(&stats).await }
+        });
+        self.primary.add_pokemon_stats(stats)
+    }
+
+    fn uncompacted_stats_days(&self, before: NaiveDate) -> BoxFuture<'_, Result<Vec<NaiveDate>, Error>> {
+        self.primary.uncompacted_stats_days(before)
+    }
+
+    fn compact_stats_day(&self, day: NaiveDate) -> BoxFuture<'_, Result<bool, Error>> {
+        // mirrors keep their own record of the compacted days
+        self.mirror("compact_stats_day", |mirror| async move { mirror.compact_stats_day(day).await });
+        self.primary.compact_stats_day(day)
+    }
+
+    fn prune_stats_days(&self, before: NaiveDate) -> BoxFuture<'_, Result<u64, Error>> {
+        self.mirror("prune_stats_days", |mirror| async move { mirror.prune_stats_days(before).await });
+        self.primary.prune_stats_days(before)
+    }
+
+    fn prune_city_stats_days(&self, before: NaiveDate) -> BoxFuture<'_, Result<u64, Error>> {
+        self.mirror("prune_city_stats_days", |mirror| async move { mirror.prune_city_stats_days(before).await });
+        self.primary.prune_city_stats_days(before)
+    }
+
+    fn update_city_lure_stats(&self, day: NaiveDate, city_id: u16, lure_id: u16) -> BoxFuture<'_, Result<(), Error>> {
+        self.mirror("update_city_lure_stats", |mirror| async move {
+            mirror.update_city_lure_stats(day, city_id, lure_id).await
+        });
+        self.primary.update_city_lure_stats(day, city_id, lure_id)
+    }
+
+    fn load_cities(&self) -> BoxFuture<'_, Result<Vec<Result<City, String>>, Error>> {
+        self.primary.load_cities()
+    }
+
+    fn city_stats(&self, city_id: u16, day: NaiveDate) -> BoxFuture<'_, Result<CityStats, Error>> {
+        self.primary.city_stats(city_id, day)
+    }
+
+    fn update_city_coordinates<'a>(
+        &'a self,
+        city_id: u16,
+        text: &'a str,
+        wkt: &'a str,
+    ) -> BoxFuture<'a, Result<bool, Error>> {
+        self.mirror("update_city_coordinates", |mirror| {
+            let (text, wkt) = (text.to_owned(), wkt.to_owned());
+            async move { mirror.update_city_coordinates(city_id, &text, &wkt).await }
+        });
+        self.primary.update_city_coordinates(city_id, text, wkt)
+    }
+
+    fn update_city_monitor(&self, city_id: u16, monitor: u8) -> BoxFuture<'_, Result<bool, Error>> {
+        self.mirror("update_city_monitor", |mirror| async move { mirror.update_city_monitor(city_id, monitor).await });
+        self.primary.update_city_monitor(city_id, monitor)
+    }
+
+    fn delete_expired<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        before: i64,
+        limit: u64,
+        archive: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        self.mirror("delete_expired", |mirror| {
+            let (table, column, archive) = (table.to_owned(), column.to_owned(), archive.map(str::to_owned));
+            async move { mirror.delete_expired(&table, &column, before, limit, archive.as_deref()).await }
+        });
+        self.primary.delete_expired(table, column, before, limit, archive)
+    }
+
+    fn clear_expired<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        columns: &'a [&'a str],
+        before: i64,
+        limit: u64,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        self.mirror("clear_expired", |mirror| {
+            let (table, column) = (table.to_owned(), column.to_owned());
+            let columns: Vec<String> = columns.iter().map(|c| (*c).to_owned()).collect();
+            async move {
+                let columns: Vec<&str> = columns.iter().map(String::as_str).collect();
+                mirror.clear_expired(&table, &column, &columns, before, limit).await
+            }
+        });
+        self.primary.clear_expired(table, column, columns, before, limit)
+    }
+}
@@ -0,0 +1,311 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::{Datelike, Days, NaiveDate};
+
+use futures_util::future::BoxFuture;
+
+use serde::Serialize;
+
+use tracing::warn;
+
+use crate::{
+    config::{Database, Pool, Profile},
+    lists::City,
+    migrations::Migration,
+    pvp::Leagues,
+    upsert::{Dialect, Upsert, Value},
+};
+
+mod mirror;
+mod mysql;
+mod postgres;
+mod sqlite;
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// per city counters, all keyed by `day`
+const CITY_STATS: [&str; 3] = ["city_stats_today", "city_lure_stats", "city_entity_stats"];
+
+/// Persistence operations needed by the engine, one implementation per database flavour
+pub trait Storage: Send + Sync {
+    /// SQL flavour spoken by the database
+    fn dialect(&self) -> Dialect;
+
+    /// Reads the latest applied migration, `None` when the schema isn't managed by hookedmap
+    fn schema_version(&self) -> BoxFuture<'_, Result<Option<u32>, Error>>;
+
+    /// Physical columns of a table, empty when the table doesn't exist
+    fn table_columns<'a>(&'a self, table: &'a str) -> BoxFuture<'a, Result<Vec<String>, Error>>;
+
+    /// Applies a migration and records it into `schema_version`
+    fn apply_migration(&self, migration: &'static Migration, applied_at: i64) -> BoxFuture<'_, Result<(), Error>>;
+
+    /// Runs an upsert, returning the number of affected rows
+    fn upsert(&self, upsert: Upsert) -> BoxFuture<'_, Result<u64, Error>>;
+
+    /// Replaces the PvP rankings of an encounter
+    fn replace_pokemon_pvp<'a>(&'a self, encounter_id: &'a str, pvp: &'a Leagues) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Creates `partition` with the same structure of `table`, if it doesn't exist yet
+    fn create_partition<'a>(&'a self, table: &'a str, partition: &'a str) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Adds the given counts to the table of their period, in a single statement
+    ///
+    /// Every stat must have the same kind of period.
+    fn add_pokemon_stats<'a>(&'a self, stats: &'a [PokemonStat]) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Days of `pokemon_stats` before `before` not yet added to the weekly and monthly tables, oldest first
+    fn uncompacted_stats_days(&self, before: NaiveDate) -> BoxFuture<'_, Result<Vec<NaiveDate>, Error>>;
+
+    /// Adds the `pokemon_stats` counts of a day to `pokemon_stats_weekly` and `pokemon_stats_monthly`
+    ///
+    /// Returns false when the day was already compacted, nothing is added twice.
+    fn compact_stats_day(&self, day: NaiveDate) -> BoxFuture<'_, Result<bool, Error>>;
+
+    /// Deletes the `pokemon_stats` rows of compacted days before `before`
+    fn prune_stats_days(&self, before: NaiveDate) -> BoxFuture<'_, Result<u64, Error>>;
+
+    /// Deletes the rows of `city_stats_today`, `city_lure_stats` and `city_entity_stats` before `before`
+    fn prune_city_stats_days(&self, before: NaiveDate) -> BoxFuture<'_, Result<u64, Error>>;
+
+    /// Counts a lure deployed inside a city
+    fn update_city_lure_stats(&self, day: NaiveDate, city_id: u16, lure_id: u16) -> BoxFuture<'_, Result<(), Error>>;
+
+    /// Reads the `city` table, a malformed row is reported on its own without failing the others
+    fn load_cities(&self) -> BoxFuture<'_, Result<Vec<Result<City, String>>, Error>>;
+
+    /// Reads the counters of a city for the given day
+    fn city_stats(&self, city_id: u16, day: NaiveDate) -> BoxFuture<'_, Result<CityStats, Error>>;
+
+    /// Replaces a city polygon, text columns get `text` as it is and spatial ones get `wkt`
+    ///
+    /// Returns false when the city isn't in the table.
+    fn update_city_coordinates<'a>(
+        &'a self,
+        city_id: u16,
+        text: &'a str,
+        wkt: &'a str,
+    ) -> BoxFuture<'a, Result<bool, Error>>;
+
+    /// Sets the `monitor` column of a city, returns false when the city isn't in the table
+    fn update_city_monitor(&self, city_id: u16, monitor: u8) -> BoxFuture<'_, Result<bool, Error>>;
+
+    /// Deletes up to `limit` rows whose `column` is older than `before`, moving them into `archive` if given
+    fn delete_expired<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        before: i64,
+        limit: u64,
+        archive: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<u64, Error>>;
+
+    /// Sets `columns` to null on up to `limit` rows whose `column` is older than `before`
+    fn clear_expired<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        columns: &'a [&'a str],
+        before: i64,
+        limit: u64,
+    ) -> BoxFuture<'a, Result<u64, Error>>;
+}
+
+/// Time span a stat is counted into
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Period {
+    /// `pokemon_stats_hourly`, by the unix timestamp the hour starts at
+    Hour(i64),
+    /// `pokemon_stats`
+    Day(NaiveDate),
+}
+
+impl Period {
+    fn table(&self) -> &'static str {
+        match self {
+            Period::Hour(_) => "pokemon_stats_hourly",
+            Period::Day(_) => "pokemon_stats",
+        }
+    }
+
+    fn column(&self) -> &'static str {
+        match self {
+            Period::Hour(_) => "hour",
+            Period::Day(_) => "date",
+        }
+    }
+}
+
+impl From<Period> for Value {
+    fn from(period: Period) -> Self {
+        match period {
+            Period::Hour(hour) => hour.into(),
+            Period::Day(day) => day.into(),
+        }
+    }
+}
+
+/// Pokemon seen during a period, `city_id` is 0 outside of every city
+#[derive(Clone)]
+pub struct PokemonStat {
+    pub period: Period,
+    pub pokemon_id: u16,
+    pub form: u16,
+    pub city_id: u16,
+    pub count: u32,
+}
+
+impl PokemonStat {
+    // in table column order: period, pokemon_id, form, city_id, count
+    fn values(&self) -> [Value; 5] {
+        [self.period.into(), self.pokemon_id.into(), self.form.into(), self.city_id.into(), self.count.into()]
+    }
+}
+
+// tables a compacted day is added to, with the column and the first day of the period containing it
+fn rollups(day: NaiveDate) -> [(&'static str, &'static str, NaiveDate); 2] {
+    let week = day - Days::new(u64::from(day.weekday().num_days_from_monday()));
+    let month = day.with_day(1).unwrap_or(day);
+    [("pokemon_stats_weekly", "week", week), ("pokemon_stats_monthly", "month", month)]
+}
+
+/// Counters of a city for a single day
+#[derive(Default, Serialize)]
+pub struct CityStats {
+    /// pokemon seen, by pokemon id
+    pub pokemon: BTreeMap<u16, u64>,
+    /// lures deployed, by lure id
+    pub lures: BTreeMap<u16, u64>,
+    /// encountered pokemon, by IV sum (45 is 100%, 0 is 0%)
+    pub iv: BTreeMap<i64, u64>,
+    /// shiny pokemon, by pokemon id
+    pub shiny: BTreeMap<i64, u64>,
+    /// raids, by level
+    pub raids: BTreeMap<i64, u64>,
+    /// quests, by type of their first reward
+    pub quest_rewards: BTreeMap<i64, u64>,
+    /// gyms, by the team controlling them when last seen
+    pub gym_teams: BTreeMap<i64, u64>,
+}
+
+impl CityStats {
+    // `city_entity_stats` rows grouped by kind and value
+    fn add_entities(&mut self, kind: &str, value: i64, count: u64) {
+        let counts = match EntityStat::from_kind(kind) {
+            Some(EntityStat::Iv) => &mut self.iv,
+            Some(EntityStat::Shiny) => &mut self.shiny,
+            Some(EntityStat::Raid) => &mut self.raids,
+            Some(EntityStat::QuestReward) => &mut self.quest_rewards,
+            Some(EntityStat::GymTeam) => &mut self.gym_teams,
+            None => return,
+        };
+        counts.insert(value, count);
+    }
+}
+
+/// What a `city_entity_stats` row counts, every entity is counted once per day
+#[derive(Clone, Copy)]
+pub enum EntityStat {
+    Iv,
+    Shiny,
+    Raid,
+    QuestReward,
+    GymTeam,
+}
+
+impl EntityStat {
+    const ALL: [EntityStat; 5] =
+        [EntityStat::Iv, EntityStat::Shiny, EntityStat::Raid, EntityStat::QuestReward, EntityStat::GymTeam];
+
+    pub fn kind(self) -> &'static str {
+        match self {
+            EntityStat::Iv => "iv",
+            EntityStat::Shiny => "shiny",
+            EntityStat::Raid => "raid",
+            EntityStat::QuestReward => "quest_reward",
+            EntityStat::GymTeam => "gym_team",
+        }
+    }
+
+    fn from_kind(kind: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|stat| stat.kind() == kind)
+    }
+}
+
+// malformed city rows are reported along with their id, when at least that is readable
+fn invalid_city(id: Option<i64>, e: impl Display) -> String {
+    match id {
+        Some(id) => format!("city {}: {}", id, e),
+        None => format!("city: {}", e),
+    }
+}
+
+// picks the implementation from the url scheme
+fn open(url: &str, statement_cache: usize, profile: Profile, pool: &Pool) -> Box<dyn Storage> {
+    match url.split_once("://") {
+        Some(("mysql", _)) => Box::new(
+            mysql::Mysql::new(url, statement_cache, profile, pool)
+                .unwrap_or_else(|e| panic!("Invalid MySQL url: {}", e)),
+        ),
+        Some(("postgres" | "postgresql", _)) => Box::new(
+            postgres::Postgres::new(url, statement_cache, profile, pool)
+                .unwrap_or_else(|e| panic!("Invalid PostgreSQL url: {}", e)),
+        ),
+        Some(("sqlite", path)) => Box::new(
+            sqlite::Sqlite::new(path, statement_cache, profile, pool)
+                .unwrap_or_else(|e| panic!("Cannot open SQLite database {}: {}", path, e)),
+        ),
+        _ => panic!("Unsupported database url: {}", url),
+    }
+}
+
+// the query shapes are finite, a cache large enough never evicts
+fn statement_cache(database: &Database) -> usize {
+    database.statement_cache.unwrap_or(128)
+}
+
+/// Opens the configured database, mirroring writes when mirrors are configured
+pub fn connect(database: &Database) -> Box<dyn Storage> {
+    let primary = open(&database.url, statement_cache(database), database.profile, &database.pool);
+    if database.mirrors.is_empty() {
+        return primary;
+    }
+
+    let mirrors = database
+        .mirrors
+        .iter()
+        .map(|mirror| Arc::from(open(&mirror.url, statement_cache(database), mirror.profile, &database.pool)))
+        .collect();
+    Box::new(mirror::Mirrored::new(primary, mirrors))
+}
+
+/// Opens the read replica, if any
+pub fn connect_reader(database: &Database) -> Option<Box<dyn Storage>> {
+    database.read_url.as_deref().map(|url| open(url, statement_cache(database), database.profile, &database.pool))
+}
+
+/// Waits for a pooled connection, logging slow waits and giving up after `acquire_timeout`
+async fn acquire<T, E, F>(pool: &Pool, conn: F) -> Result<T, Error>
+where
+    F: Future<Output = Result<T, E>>,
+    E: Into<Error>,
+{
+    let start = Instant::now();
+    let conn = match pool.acquire_timeout {
+        Some(timeout) => tokio::time::timeout(Duration::from_secs(timeout), conn)
+            .await
+            .map_err(|_| format!("no connection available after {}s", timeout))?,
+        None => conn.await,
+    };
+    let elapsed = start.elapsed();
+    if elapsed >= Duration::from_millis(pool.slow_acquire.unwrap_or(500)) {
+        warn!("slow connection acquire: {}ms", elapsed.as_millis());
+    }
+    conn.map_err(Into::into)
+}
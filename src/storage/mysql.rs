@@ -0,0 +1,543 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use chrono::NaiveDate;
+
+use futures_util::future::BoxFuture;
+
+use mysql_async::{
+    params,
+    prelude::{FromValue, Queryable},
+    Conn, Opts, OptsBuilder, Params, Pool, PoolConstraints, Row, Statement, TxOpts,
+};
+
+use super::{acquire, invalid_city, rollups, CityStats, Error, PokemonStat, Storage, CITY_STATS};
+
+use crate::{
+    config::{Pool as PoolConfig, Profile},
+    lists::City,
+    metrics::STATEMENTS,
+    migrations::Migration,
+    pvp::Leagues,
+    upsert::{Dialect, Upsert, Value},
+};
+
+pub struct Mysql {
+    pool: Pool,
+    config: PoolConfig,
+    profile: Profile,
+    statement_cache: usize,
+    max_connections: usize,
+    // upsert statements prepared on each connection, by connection id and query
+    prepared: Mutex<HashMap<u32, HashMap<String, Statement>>>,
+}
+
+impl Mysql {
+    pub fn new(url: &str, statement_cache: usize, profile: Profile, config: &PoolConfig) -> Result<Self, Error> {
+        let opts = Opts::from_url(url)?;
+
+        let mut pool_opts = opts.pool_opts().clone();
+        if config.min_connections.is_some() || config.max_connections.is_some() {
+            let constraints = pool_opts.constraints();
+            pool_opts = pool_opts.with_constraints(
+                PoolConstraints::new(
+                    config.min_connections.unwrap_or(constraints.min()),
+                    config.max_connections.unwrap_or(constraints.max()),
+                )
+                .ok_or("min_connections can't be greater than max_connections")?,
+            );
+        }
+        if let Some(ttl) = config.idle_ttl {
+            pool_opts = pool_opts.with_inactive_connection_ttl(Duration::from_secs(ttl));
+        }
+        // a reset deallocates every prepared statement, the session isn't changed in ways it would undo
+        pool_opts = pool_opts.with_reset_connection(false);
+        let max_connections = pool_opts.constraints().max();
+
+        // exec_* calls prepare through the connection cache, it only has to be big enough
+        let mut builder = OptsBuilder::from_opts(opts).stmt_cache_size(statement_cache).pool_opts(pool_opts);
+        if let Some(keepalive) = config.tcp_keepalive {
+            builder = builder.tcp_keepalive(Some(u32::try_from(keepalive * 1000)?));
+        }
+        if let Some(timeout) = config.statement_timeout {
+            builder = builder.init(vec![format!("SET SESSION max_execution_time = {}", timeout * 1000)]);
+        }
+
+        Ok(Mysql {
+            pool: Pool::new(builder),
+            config: config.clone(),
+            profile,
+            statement_cache,
+            max_connections,
+            prepared: Mutex::default(),
+        })
+    }
+
+    async fn conn(&self) -> Result<Conn, Error> {
+        acquire(&self.config, self.pool.get_conn()).await
+    }
+
+    // prepares an upsert shape the first time a connection sees it
+    async fn prepare(&self, conn: &mut Conn, query: &str) -> Result<Statement, Error> {
+        let cached = self.prepared.lock().ok().and_then(|prepared| prepared.get(&conn.id())?.get(query).cloned());
+        if let Some(statement) = cached {
+            STATEMENTS.hit();
+            return Ok(statement);
+        }
+        STATEMENTS.miss();
+        let statement = conn.prep(query).await?;
+        if let Ok(mut prepared) = self.prepared.lock() {
+            // connections closed by the pool leave their statements behind, live ones prepare them again
+            if !prepared.contains_key(&conn.id()) && prepared.len() >= self.max_connections {
+                prepared.clear();
+            }
+            let statements = prepared.entry(conn.id()).or_default();
+            // shapes are finite, only a misconfigured merge could fill it
+            if statements.len() >= self.statement_cache {
+                statements.clear();
+            }
+            statements.insert(query.to_owned(), statement.clone());
+        }
+        Ok(statement)
+    }
+
+    fn forget(&self, conn: &Conn, query: &str) {
+        if let Ok(mut prepared) = self.prepared.lock() {
+            if let Some(statements) = prepared.get_mut(&conn.id()) {
+                statements.remove(query);
+            }
+        }
+    }
+}
+
+impl From<Value> for mysql_async::Value {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => mysql_async::Value::NULL,
+            Value::Bool(b) => b.into(),
+            Value::Int(i) => i.into(),
+            Value::UInt(u) => u.into(),
+            Value::Float(f) => f.into(),
+            Value::Text(s) => s.into(),
+            Value::Date(d) => d.into(),
+            Value::DateTime(d) => d.into(),
+        }
+    }
+}
+
+// ER_UNKNOWN_STMT_HANDLER
+const UNKNOWN_STATEMENT: u16 = 1243;
+
+fn column<T: FromValue>(row: &mut Row, name: &str) -> Result<T, Error> {
+    row.take_opt(name)
+        .ok_or_else(|| format!("missing city.{}", name))?
+        .map_err(|e| format!("invalid city.{}: {}", name, e).into())
+}
+
+// spatial columns are read as GeoJSON and written as WKT, text ones are used as they are
+async fn spatial(conn: &mut Conn) -> Result<bool, Error> {
+    let kind: Option<String> = conn
+        .query_first(
+            "SELECT DATA_TYPE FROM information_schema.columns WHERE table_schema = DATABASE() AND table_name = 'city' AND column_name = 'coordinates'",
+        )
+        .await?;
+    Ok(matches!(kind.as_deref(), Some("geometry" | "polygon" | "multipolygon" | "geometrycollection")))
+}
+
+// affected rows only count changed rows, an update writing the same value would look like a missing city
+async fn city_exists(conn: &mut Conn, city_id: u16) -> Result<bool, Error> {
+    let found: Option<u8> = conn.exec_first("SELECT 1 FROM city WHERE id = ?", (city_id,)).await?;
+    Ok(found.is_some())
+}
+
+fn city(mut row: Row) -> Result<City, Error> {
+    Ok(City::new(
+        column(&mut row, "id")?,
+        column(&mut row, "name")?,
+        &column::<String>(&mut row, "coordinates")?,
+        column(&mut row, "scadenza")?,
+        column(&mut row, "monitor")?,
+        &column::<String>(&mut row, "admins_users")?,
+        column::<Option<String>>(&mut row, "timezone")?.as_deref(),
+    )?)
+}
+
+// without archive the rows needn't be picked first, tables without id can be pruned too
+fn delete_expired(table: &str, column: &str, limit: u64) -> String {
+    format!("DELETE FROM `{}` WHERE `{}` < ? ORDER BY `{}` LIMIT {}", table, column, column, limit)
+}
+
+fn lock_expired(table: &str, id: &str, column: &str, limit: u64) -> String {
+    format!("SELECT `{}` FROM `{}` WHERE `{}` < ? ORDER BY `{}` LIMIT {} FOR UPDATE", id, table, column, column, limit)
+}
+
+impl Storage for Mysql {
+    fn dialect(&self) -> Dialect {
+        Dialect::Mysql
+    }
+
+    fn schema_version(&self) -> BoxFuture<'_, Result<Option<u32>, Error>> {
+        Box::pin(async move {
+            let mut conn = self.conn().await?;
+            let managed: Option<bool> = conn
+                .query_first(
+                    "SELECT COUNT(*) > 0 FROM information_schema.tables WHERE table_schema = DATABASE() AND table_name = 'schema_version'",
+                )
+                .await?;
+            if managed != Some(true) {
+                return Ok(None);
+            }
+            Ok(conn.query_first("SELECT COALESCE(MAX(version), 0) FROM schema_version").await?)
+        })
+    }
+
+    fn table_columns<'a>(&'a self, table: &'a str) -> BoxFuture<'a, Result<Vec<String>, Error>> {
+        Box::pin(async move {
+            let mut conn = self.conn().await?;
+            Ok(conn
+                .exec(
+                    "SELECT column_name FROM information_schema.columns WHERE table_schema = DATABASE() AND table_name = ?",
+                    (table,),
+                )
+                .await?)
+        })
+    }
+
+    fn apply_migration(&self, migration: &'static Migration, applied_at: i64) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            // DDL statements are committed implicitly and a crash can happen before the version is recorded,
+            // every MySQL migration checks what it changes so that running it again is harmless
+            let mut conn = self.conn().await?;
+            conn.query_drop(migration.sql(self.profile)).await?;
+            conn.exec_drop(
+                "INSERT INTO schema_version (version, name, applied_at) VALUES (:version, :name, :applied_at)",
+                params! {
+                    "version" => migration.version,
+                    "name" => migration.name,
+                    "applied_at" => applied_at,
+                },
+            )
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn upsert(&self, upsert: Upsert) -> BoxFuture<'_, Result<u64, Error>> {
+        Box::pin(async move {
+            let (query, params) = upsert.build(Dialect::Mysql, self.profile);
+            let params = Params::Positional(params.into_iter().map(Into::into).collect());
+            let mut conn = self.conn().await?;
+            let statement = self.prepare(&mut conn, &query).await?;
+            match conn.exec_drop(&statement, params.clone()).await {
+                // the driver cache closes the statements it evicts, prepare it again
+                Err(mysql_async::Error::Server(e)) if e.code == UNKNOWN_STATEMENT => {
+                    self.forget(&conn, &query);
+                    let statement = self.prepare(&mut conn, &query).await?;
+                    conn.exec_drop(&statement, params).await?;
+                }
+                result => result?,
+            }
+            Ok(conn.affected_rows())
+        })
+    }
+
+    fn replace_pokemon_pvp<'a>(&'a self, encounter_id: &'a str, pvp: &'a Leagues) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let mut conn = self.conn().await?;
+            let mut tx = conn.start_transaction(TxOpts::default()).await?;
+            tx.exec_drop("DELETE FROM pokemon_pvp WHERE encounter_id = :id", params! { "id" => encounter_id }).await?;
+            tx.exec_batch(
+                "INSERT INTO pokemon_pvp (encounter_id, league, pokemon_id, form, `rank`, cp, level, percentage)
+                VALUES (:id, :league, :pokemon, :form, :rank, :cp, :level, :percentage)",
+                pvp.iter().flat_map(|(league, rankings)| {
+                    rankings.iter().map(move |ranking| {
+                        params! {
+                            "id" => encounter_id,
+                            "league" => league.as_str(),
+                            "pokemon" => ranking.pokemon,
+                            "form" => ranking.form.unwrap_or_default(),
+                            "rank" => ranking.rank,
+                            "cp" => ranking.cp,
+                            "level" => ranking.level,
+                            "percentage" => ranking.percentage,
+                        }
+                    })
+                }),
+            )
+            .await?;
+            tx.commit().await?;
+            Ok(())
+        })
+    }
+
+    fn create_partition<'a>(&'a self, table: &'a str, partition: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let mut conn = self.conn().await?;
+            conn.query_drop(format!("CREATE TABLE IF NOT EXISTS `{}` LIKE `{}`", partition, table)).await?;
+            Ok(())
+        })
+    }
+
+    fn add_pokemon_stats<'a>(&'a self, stats: &'a [PokemonStat]) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let Some(first) = stats.first() else {
+                return Ok(());
+            };
+            let rows = vec!["(?, ?, ?, ?, ?)"; stats.len()].join(", ");
+            let params = stats.iter().flat_map(PokemonStat::values).map(Into::into).collect();
+            let mut conn = self.conn().await?;
+            conn.exec_drop(
+                format!("INSERT INTO {} (`{}`, `pokemon_id`, `form`, `city_id`, `count`) VALUES {} ON DUPLICATE KEY UPDATE `count` = `count` + VALUES(`count`)", first.period.table(), first.period.column(), rows),
+                Params::Positional(params),
+            )
+            .await?;
+            Ok(())
+        })
+    }
+
+    fn uncompacted_stats_days(&self, before: NaiveDate) -> BoxFuture<'_, Result<Vec<NaiveDate>, Error>> {
+        Box::pin(async move {
+            let mut conn = self.conn().await?;
+            let days = conn
+                .exec(
+                    "SELECT DISTINCT `date` FROM pokemon_stats WHERE `date` < ? AND `date` NOT IN (SELECT `day` FROM stats_compacted) ORDER BY `date`",
+                    (before,),
+                )
+                .await?;
+            Ok(days)
+        })
+    }
+
+    fn compact_stats_day(&self, day: NaiveDate) -> BoxFuture<'_, Result<bool, Error>> {
+        Box::pin(async move {
+            let mut conn = self.conn().await?;
+            let mut tx = conn.start_transaction(TxOpts::default()).await?;
+            tx.exec_drop("INSERT IGNORE INTO stats_compacted (`day`) VALUES (?)", (day,)).await?;
+            if tx.affected_rows() == 0 {
+                tx.rollback().await?;
+                return Ok(false);
+            }
+            for (table, column, start) in rollups(day) {
+                // aggregates can't be referenced by ON DUPLICATE KEY UPDATE, unless they come from a derived table
+                tx.exec_drop(
+                    format!(
+                        "INSERT INTO {} (`{}`, `pokemon_id`, `form`, `city_id`, `count`)
+                        SELECT * FROM (SELECT ? AS period, pokemon_id, form, city_id, SUM(`count`) AS total FROM pokemon_stats WHERE `date` = ? GROUP BY pokemon_id, form, city_id) AS compacted
+                        ON DUPLICATE KEY UPDATE `count` = `count` + compacted.total",
+                        table, column
+                    ),
+                    (start, day),
+                )
+                .await?;
+            }
+            tx.commit().await?;
+            Ok(true)
+        })
+    }
+
+    fn prune_stats_days(&self, before: NaiveDate) -> BoxFuture<'_, Result<u64, Error>> {
+        Box::pin(async move {
+            let mut conn = self.conn().await?;
+            conn.exec_drop(
+                "DELETE FROM pokemon_stats WHERE `date` < ? AND `date` IN (SELECT `day` FROM stats_compacted)",
+                (before,),
+            )
+            .await?;
+            Ok(conn.affected_rows())
+        })
+    }
+
+    fn prune_city_stats_days(&self, before: NaiveDate) -> BoxFuture<'_, Result<u64, Error>> {
+        Box::pin(async move {
+            let mut conn = self.conn().await?;
+            let mut deleted = 0;
+            for table in CITY_STATS {
+                conn.exec_drop(format!("DELETE FROM `{}` WHERE `day` < ?", table), (before,)).await?;
+                deleted += conn.affected_rows();
+            }
+            Ok(deleted)
+        })
+    }
+
+    fn update_city_lure_stats(&self, day: NaiveDate, city_id: u16, lure_id: u16) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            let mut conn = self.conn().await?;
+            conn.exec_drop("INSERT INTO city_lure_stats (day, city_id, lure_id, count) VALUES (:day, :city_id, :lure_id, 1) ON DUPLICATE KEY UPDATE count = count + 1", params! {
+                "day" => day,
+                "city_id" => city_id,
+                "lure_id" => lure_id,
+            }).await?;
+            Ok(())
+        })
+    }
+
+    fn load_cities(&self) -> BoxFuture<'_, Result<Vec<Result<City, String>>, Error>> {
+        Box::pin(async move {
+            let mut conn = self.conn().await?;
+            let coordinates =
+                if spatial(&mut conn).await? { "ST_AsGeoJSON(coordinates) AS coordinates" } else { "coordinates" };
+            let rows: Vec<Row> = conn
+                .query(format!("SELECT id, name, {}, scadenza, monitor, admins_users, timezone FROM city", coordinates))
+                .await?;
+            Ok(rows
+                .into_iter()
+                .map(|row| {
+                    let id = row.get_opt::<i64, _>("id").and_then(Result::ok);
+                    city(row).map_err(|e| invalid_city(id, e))
+                })
+                .collect())
+        })
+    }
+
+    fn city_stats(&self, city_id: u16, day: NaiveDate) -> BoxFuture<'_, Result<CityStats, Error>> {
+        Box::pin(async move {
+            let mut conn = self.conn().await?;
+            let pokemon: Vec<(u16, u64)> = conn
+                .exec(
+                    "SELECT pokemon_id, COUNT(*) FROM city_stats_today WHERE day = ? AND city_id = ? GROUP BY pokemon_id",
+                    (day, city_id),
+                )
+                .await?;
+            let lures: Vec<(u16, u64)> = conn
+                .exec("SELECT lure_id, count FROM city_lure_stats WHERE day = ? AND city_id = ?", (day, city_id))
+                .await?;
+            let entities: Vec<(String, i64, u64)> = conn
+                .exec(
+                    "SELECT kind, value, COUNT(*) FROM city_entity_stats WHERE day = ? AND city_id = ? GROUP BY kind, value",
+                    (day, city_id),
+                )
+                .await?;
+
+            let mut stats = CityStats {
+                pokemon: pokemon.into_iter().collect(),
+                lures: lures.into_iter().collect(),
+                ..Default::default()
+            };
+            for (kind, value, count) in entities {
+                stats.add_entities(&kind, value, count);
+            }
+            Ok(stats)
+        })
+    }
+
+    fn update_city_coordinates<'a>(
+        &'a self,
+        city_id: u16,
+        text: &'a str,
+        wkt: &'a str,
+    ) -> BoxFuture<'a, Result<bool, Error>> {
+        Box::pin(async move {
+            let mut conn = self.conn().await?;
+            if !city_exists(&mut conn, city_id).await? {
+                return Ok(false);
+            }
+            if spatial(&mut conn).await? {
+                // keep the SRID of the column, WKT is always longitude first
+                conn.exec_drop(
+                    "UPDATE city SET coordinates = ST_GeomFromText(?, ST_SRID(coordinates), 'axis-order=long-lat') WHERE id = ?",
+                    (wkt, city_id),
+                )
+                .await?;
+            } else {
+                conn.exec_drop("UPDATE city SET coordinates = ? WHERE id = ?", (text, city_id)).await?;
+            }
+            Ok(true)
+        })
+    }
+
+    fn update_city_monitor(&self, city_id: u16, monitor: u8) -> BoxFuture<'_, Result<bool, Error>> {
+        Box::pin(async move {
+            let mut conn = self.conn().await?;
+            if !city_exists(&mut conn, city_id).await? {
+                return Ok(false);
+            }
+            conn.exec_drop("UPDATE city SET monitor = ? WHERE id = ?", (monitor, city_id)).await?;
+            Ok(true)
+        })
+    }
+
+    fn delete_expired<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        before: i64,
+        limit: u64,
+        archive: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        Box::pin(async move {
+            let Some((column, before)) = self.profile.timestamp(table, column, before) else {
+                return Ok(0);
+            };
+            let mut conn = self.conn().await?;
+            let Some(archive) = archive else {
+                conn.exec_drop(delete_expired(table, &column, limit), (mysql_async::Value::from(before),)).await?;
+                return Ok(conn.affected_rows());
+            };
+            let id = self.profile.columns(table, &["id"]).pop().ok_or_else(|| format!("{} has no id", table))?;
+
+            let mut tx = conn.start_transaction(TxOpts::default()).await?;
+            // rows are picked once and locked, so that archive and delete see exactly the same ones
+            let ids: Vec<mysql_async::Value> =
+                tx.exec(lock_expired(table, &id, &column, limit), (mysql_async::Value::from(before),)).await?;
+            if ids.is_empty() {
+                return Ok(0);
+            }
+            let condition = format!("FROM `{}` WHERE `{}` IN ({})", table, id, vec!["?"; ids.len()].join(", "));
+            tx.exec_drop(format!("INSERT IGNORE INTO `{}` SELECT * {}", archive, condition), ids.clone()).await?;
+            tx.exec_drop(format!("DELETE {}", condition), ids).await?;
+            let deleted = tx.affected_rows();
+            tx.commit().await?;
+            Ok(deleted)
+        })
+    }
+
+    fn clear_expired<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        columns: &'a [&'a str],
+        before: i64,
+        limit: u64,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        Box::pin(async move {
+            let Some((column, before)) = self.profile.timestamp(table, column, before) else {
+                return Ok(0);
+            };
+            let assignments = self
+                .profile
+                .columns(table, columns)
+                .iter()
+                .map(|name| format!("`{}` = NULL", name))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            let mut conn = self.conn().await?;
+            conn.exec_drop(
+                format!("UPDATE `{}` SET {} WHERE `{}` < ? LIMIT {}", table, assignments, column, limit),
+                (mysql_async::Value::from(before),),
+            )
+            .await?;
+            Ok(conn.affected_rows())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delete_expired_hourly_stats() {
+        assert_eq!(
+            delete_expired("pokemon_stats_hourly", "hour", 1000),
+            "DELETE FROM `pokemon_stats_hourly` WHERE `hour` < ? ORDER BY `hour` LIMIT 1000"
+        );
+    }
+
+    #[test]
+    fn lock_expired_pokemon() {
+        assert_eq!(
+            lock_expired("pokemon", "id", "expire_timestamp", 500),
+            "SELECT `id` FROM `pokemon` WHERE `expire_timestamp` < ? ORDER BY `expire_timestamp` LIMIT 500 FOR UPDATE"
+        );
+    }
+}
@@ -0,0 +1,503 @@
+use std::{str::FromStr, time::Duration};
+
+use bytes::BytesMut;
+
+use chrono::NaiveDate;
+
+use deadpool_postgres::{Client, Manager, Pool};
+
+use futures_util::future::BoxFuture;
+
+use tokio_postgres::{
+    types::{to_sql_checked, IsNull, ToSql, Type},
+    NoTls, Row,
+};
+
+use super::{acquire, invalid_city, rollups, CityStats, Error, PokemonStat, Storage, CITY_STATS};
+
+use crate::{
+    config::{Pool as PoolConfig, Profile},
+    lists::City,
+    metrics::STATEMENTS,
+    migrations::Migration,
+    pvp::Leagues,
+    upsert::{Dialect, Upsert, Value},
+};
+
+pub struct Postgres {
+    pool: Pool,
+    config: PoolConfig,
+    statement_cache: usize,
+    profile: Profile,
+}
+
+impl Postgres {
+    pub fn new(url: &str, statement_cache: usize, profile: Profile, config: &PoolConfig) -> Result<Self, Error> {
+        let mut pg_config = tokio_postgres::Config::from_str(url)?;
+        if let Some(timeout) = config.statement_timeout {
+            pg_config.options(format!("-c statement_timeout={}s", timeout));
+        }
+        if let Some(keepalive) = config.tcp_keepalive {
+            pg_config.keepalives(true).keepalives_idle(Duration::from_secs(keepalive));
+        }
+
+        let manager = Manager::new(pg_config, NoTls);
+        let mut builder = Pool::builder(manager);
+        if let Some(max) = config.max_connections {
+            builder = builder.max_size(max);
+        }
+        let pool = builder.build()?;
+
+        // deadpool never closes idle connections by itself
+        if let Some(ttl) = config.idle_ttl {
+            let pool = pool.clone();
+            let ttl = Duration::from_secs(ttl);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(ttl.min(Duration::from_secs(60)));
+                loop {
+                    interval.tick().await;
+                    pool.retain(|_, metrics| metrics.last_used() < ttl);
+                }
+            });
+        }
+
+        Ok(Postgres { pool, config: config.clone(), statement_cache, profile })
+    }
+
+    async fn client(&self) -> Result<Client, Error> {
+        acquire(&self.config, self.pool.get()).await
+    }
+}
+
+// PostgreSQL is strict on types, values are encoded following the type of the column they're written into
+impl ToSql for Value {
+    fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, Error> {
+        match self {
+            Value::Null => Ok(IsNull::Yes),
+            Value::Bool(b) if *ty == Type::BOOL => b.to_sql(ty, out),
+            Value::Bool(b) => int_to_sql(i64::from(*b), ty, out),
+            Value::Int(i) => int_to_sql(*i, ty, out),
+            // unsigned 64 bits ids are stored bit by bit into bigint columns
+            Value::UInt(u) => int_to_sql(*u as i64, ty, out),
+            Value::Float(f) if *ty == Type::FLOAT4 => (*f as f32).to_sql(ty, out),
+            Value::Float(f) => f.to_sql(ty, out),
+            Value::Text(s) => s.as_str().to_sql(ty, out),
+            Value::Date(d) => d.to_sql(ty, out),
+            Value::DateTime(d) => d.to_sql(ty, out),
+        }
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+
+    to_sql_checked!();
+}
+
+fn int_to_sql(i: i64, ty: &Type, out: &mut BytesMut) -> Result<IsNull, Error> {
+    match *ty {
+        Type::BOOL => (i != 0).to_sql(ty, out),
+        Type::INT2 => i16::try_from(i)?.to_sql(ty, out),
+        Type::INT4 => i32::try_from(i)?.to_sql(ty, out),
+        Type::FLOAT4 => (i as f32).to_sql(ty, out),
+        Type::FLOAT8 => (i as f64).to_sql(ty, out),
+        Type::TEXT | Type::VARCHAR => i.to_string().to_sql(ty, out),
+        _ => i.to_sql(ty, out),
+    }
+}
+
+fn as_params(values: &[Value]) -> Vec<&(dyn ToSql + Sync)> {
+    values.iter().map(|v| v as &(dyn ToSql + Sync)).collect()
+}
+
+// PostGIS columns are read as GeoJSON and written as WKT, text ones are used as they are
+async fn coordinates_kind(client: &Client) -> Result<Option<String>, Error> {
+    Ok(client
+        .query_opt(
+            "SELECT udt_name::text FROM information_schema.columns WHERE table_schema = current_schema() AND table_name = 'city' AND column_name = 'coordinates'",
+            &[],
+        )
+        .await?
+        .map(|row| row.try_get::<_, String>(0))
+        .transpose()?)
+}
+
+fn city(row: &Row) -> Result<City, Error> {
+    Ok(City::new(
+        u16::try_from(row.try_get::<_, i32>("id")?)?,
+        row.try_get("name")?,
+        row.try_get("coordinates")?,
+        row.try_get("scadenza")?,
+        u8::try_from(row.try_get::<_, i16>("monitor")?)?,
+        row.try_get("admins_users")?,
+        row.try_get("timezone")?,
+    )?)
+}
+
+impl Storage for Postgres {
+    fn dialect(&self) -> Dialect {
+        Dialect::Postgres
+    }
+
+    fn schema_version(&self) -> BoxFuture<'_, Result<Option<u32>, Error>> {
+        Box::pin(async move {
+            let client = self.client().await?;
+            let managed: bool =
+                client.query_one("SELECT to_regclass('schema_version') IS NOT NULL", &[]).await?.try_get(0)?;
+            if !managed {
+                return Ok(None);
+            }
+            let version: i32 =
+                client.query_one("SELECT COALESCE(MAX(version), 0) FROM schema_version", &[]).await?.try_get(0)?;
+            Ok(Some(u32::try_from(version)?))
+        })
+    }
+
+    fn table_columns<'a>(&'a self, table: &'a str) -> BoxFuture<'a, Result<Vec<String>, Error>> {
+        Box::pin(async move {
+            let client = self.client().await?;
+            let rows = client
+                .query(
+                    "SELECT column_name::text FROM information_schema.columns WHERE table_schema = current_schema() AND table_name = $1",
+                    &[&table],
+                )
+                .await?;
+            Ok(rows.iter().map(|row| row.try_get(0)).collect::<Result<_, _>>()?)
+        })
+    }
+
+    fn apply_migration(&self, migration: &'static Migration, applied_at: i64) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            // PostgreSQL DDL is transactional, a failing migration leaves no trace
+            let mut client = self.client().await?;
+            let tx = client.transaction().await?;
+            tx.batch_execute(&migration.sql(self.profile)).await?;
+            tx.execute(
+                "INSERT INTO schema_version (version, name, applied_at) VALUES ($1, $2, $3)",
+                &[&Value::from(migration.version), &Value::from(migration.name), &Value::from(applied_at)],
+            )
+            .await?;
+            tx.commit().await?;
+            Ok(())
+        })
+    }
+
+    fn upsert(&self, upsert: Upsert) -> BoxFuture<'_, Result<u64, Error>> {
+        Box::pin(async move {
+            let (query, params) = upsert.build(Dialect::Postgres, self.profile);
+            let client = self.client().await?;
+            // the driver cache is unbounded, drop it instead of letting it grow on a misconfigured merge
+            if client.statement_cache.size() >= self.statement_cache {
+                client.statement_cache.clear();
+            }
+            let cached = client.statement_cache.size();
+            let statement = client.prepare_cached(&query).await?;
+            if client.statement_cache.size() > cached {
+                STATEMENTS.miss();
+            } else {
+                STATEMENTS.hit();
+            }
+            Ok(client.execute(&statement, &as_params(&params)).await?)
+        })
+    }
+
+    fn replace_pokemon_pvp<'a>(&'a self, encounter_id: &'a str, pvp: &'a Leagues) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let mut client = self.client().await?;
+            let tx = client.transaction().await?;
+            tx.execute("DELETE FROM pokemon_pvp WHERE encounter_id = $1", &[&encounter_id]).await?;
+            let insert = tx
+                .prepare(
+                    "INSERT INTO pokemon_pvp (encounter_id, league, pokemon_id, form, rank, cp, level, percentage)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+                )
+                .await?;
+            for (league, rankings) in pvp {
+                for ranking in rankings {
+                    let values: [Value; 8] = [
+                        encounter_id.into(),
+                        league.as_str().into(),
+                        ranking.pokemon.into(),
+                        ranking.form.unwrap_or_default().into(),
+                        ranking.rank.into(),
+                        ranking.cp.into(),
+                        ranking.level.into(),
+                        ranking.percentage.into(),
+                    ];
+                    tx.execute(&insert, &as_params(&values)).await?;
+                }
+            }
+            tx.commit().await?;
+            Ok(())
+        })
+    }
+
+    fn create_partition<'a>(&'a self, table: &'a str, partition: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let client = self.client().await?;
+            client
+                .batch_execute(&format!(
+                    "CREATE TABLE IF NOT EXISTS \"{}\" (LIKE \"{}\" INCLUDING ALL)",
+                    partition, table
+                ))
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn add_pokemon_stats<'a>(&'a self, stats: &'a [PokemonStat]) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let Some(first) = stats.first() else {
+                return Ok(());
+            };
+            let (table, column) = (first.period.table(), first.period.column());
+            let rows: Vec<String> = (0..stats.len())
+                .map(|i| format!("(${}, ${}, ${}, ${}, ${})", i * 5 + 1, i * 5 + 2, i * 5 + 3, i * 5 + 4, i * 5 + 5))
+                .collect();
+            let params: Vec<Value> = stats.iter().flat_map(PokemonStat::values).collect();
+            let client = self.client().await?;
+            client
+                .execute(
+                    &format!(
+                        "INSERT INTO {} (\"{}\", pokemon_id, form, city_id, count) VALUES {}
+                        ON CONFLICT (\"{}\", pokemon_id, form, city_id) DO UPDATE SET count = {}.count + excluded.count",
+                        table,
+                        column,
+                        rows.join(", "),
+                        column,
+                        table
+                    ),
+                    &as_params(&params),
+                )
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn uncompacted_stats_days(&self, before: NaiveDate) -> BoxFuture<'_, Result<Vec<NaiveDate>, Error>> {
+        Box::pin(async move {
+            let client = self.client().await?;
+            let rows = client
+                .query(
+                    "SELECT DISTINCT \"date\" FROM pokemon_stats WHERE \"date\" < $1 AND \"date\" NOT IN (SELECT day FROM stats_compacted) ORDER BY \"date\"",
+                    &[&before],
+                )
+                .await?;
+            Ok(rows.iter().map(|row| row.try_get(0)).collect::<Result<_, _>>()?)
+        })
+    }
+
+    fn compact_stats_day(&self, day: NaiveDate) -> BoxFuture<'_, Result<bool, Error>> {
+        Box::pin(async move {
+            let mut client = self.client().await?;
+            let tx = client.transaction().await?;
+            if tx.execute("INSERT INTO stats_compacted (day) VALUES ($1) ON CONFLICT DO NOTHING", &[&day]).await? == 0 {
+                return Ok(false);
+            }
+            for (table, column, start) in rollups(day) {
+                tx.execute(
+                    &format!(
+                        "INSERT INTO {} ({}, pokemon_id, form, city_id, count)
+                        SELECT $1, pokemon_id, form, city_id, SUM(count) FROM pokemon_stats WHERE \"date\" = $2 GROUP BY pokemon_id, form, city_id
+                        ON CONFLICT ({}, pokemon_id, form, city_id) DO UPDATE SET count = {}.count + excluded.count",
+                        table, column, column, table
+                    ),
+                    &[&start, &day],
+                )
+                .await?;
+            }
+            tx.commit().await?;
+            Ok(true)
+        })
+    }
+
+    fn prune_stats_days(&self, before: NaiveDate) -> BoxFuture<'_, Result<u64, Error>> {
+        Box::pin(async move {
+            let client = self.client().await?;
+            Ok(client
+                .execute(
+                    "DELETE FROM pokemon_stats WHERE \"date\" < $1 AND \"date\" IN (SELECT day FROM stats_compacted)",
+                    &[&before],
+                )
+                .await?)
+        })
+    }
+
+    fn prune_city_stats_days(&self, before: NaiveDate) -> BoxFuture<'_, Result<u64, Error>> {
+        Box::pin(async move {
+            let client = self.client().await?;
+            let mut deleted = 0;
+            for table in CITY_STATS {
+                deleted += client.execute(&format!("DELETE FROM {} WHERE day < $1", table), &[&before]).await?;
+            }
+            Ok(deleted)
+        })
+    }
+
+    fn update_city_lure_stats(&self, day: NaiveDate, city_id: u16, lure_id: u16) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            let client = self.client().await?;
+            client
+                .execute(
+                    "INSERT INTO city_lure_stats (day, city_id, lure_id, count) VALUES ($1, $2, $3, 1)
+                    ON CONFLICT (day, city_id, lure_id) DO UPDATE SET count = city_lure_stats.count + 1",
+                    &[&Value::from(day), &Value::from(city_id), &Value::from(lure_id)],
+                )
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn load_cities(&self) -> BoxFuture<'_, Result<Vec<Result<City, String>>, Error>> {
+        Box::pin(async move {
+            let client = self.client().await?;
+            let coordinates = match coordinates_kind(&client).await?.as_deref() {
+                Some("geometry" | "geography") => "ST_AsGeoJSON(coordinates) AS coordinates",
+                _ => "coordinates",
+            };
+            let rows = client
+                .query(
+                    &format!("SELECT id, name, {}, scadenza, monitor, admins_users, timezone FROM city", coordinates),
+                    &[],
+                )
+                .await?;
+            Ok(rows
+                .iter()
+                .map(|row| city(row).map_err(|e| invalid_city(row.try_get::<_, i32>("id").ok().map(i64::from), e)))
+                .collect())
+        })
+    }
+
+    fn city_stats(&self, city_id: u16, day: NaiveDate) -> BoxFuture<'_, Result<CityStats, Error>> {
+        Box::pin(async move {
+            let client = self.client().await?;
+            let city_id = i32::from(city_id);
+            let mut stats = CityStats::default();
+            for row in client
+                .query(
+                    "SELECT pokemon_id, COUNT(*) FROM city_stats_today WHERE day = $1 AND city_id = $2 GROUP BY pokemon_id",
+                    &[&day, &city_id],
+                )
+                .await?
+            {
+                stats.pokemon.insert(u16::try_from(row.try_get::<_, i32>(0)?)?, u64::try_from(row.try_get::<_, i64>(1)?)?);
+            }
+            for row in client
+                .query("SELECT lure_id, count FROM city_lure_stats WHERE day = $1 AND city_id = $2", &[&day, &city_id])
+                .await?
+            {
+                stats
+                    .lures
+                    .insert(u16::try_from(row.try_get::<_, i32>(0)?)?, u64::try_from(row.try_get::<_, i32>(1)?)?);
+            }
+            for row in client
+                .query(
+                    "SELECT kind, value, COUNT(*) FROM city_entity_stats WHERE day = $1 AND city_id = $2 GROUP BY kind, value",
+                    &[&day, &city_id],
+                )
+                .await?
+            {
+                stats.add_entities(
+                    row.try_get(0)?,
+                    i64::from(row.try_get::<_, i32>(1)?),
+                    u64::try_from(row.try_get::<_, i64>(2)?)?,
+                );
+            }
+            Ok(stats)
+        })
+    }
+
+    fn update_city_coordinates<'a>(
+        &'a self,
+        city_id: u16,
+        text: &'a str,
+        wkt: &'a str,
+    ) -> BoxFuture<'a, Result<bool, Error>> {
+        Box::pin(async move {
+            let client = self.client().await?;
+            let (query, value) = match coordinates_kind(&client).await?.as_deref() {
+                // keep the SRID of the column
+                Some("geometry") => {
+                    ("UPDATE city SET coordinates = ST_GeomFromText($1, ST_SRID(coordinates)) WHERE id = $2", wkt)
+                }
+                Some("geography") => ("UPDATE city SET coordinates = ST_GeogFromText($1) WHERE id = $2", wkt),
+                _ => ("UPDATE city SET coordinates = $1 WHERE id = $2", text),
+            };
+            Ok(client.execute(query, &[&value, &i32::from(city_id)]).await? > 0)
+        })
+    }
+
+    fn update_city_monitor(&self, city_id: u16, monitor: u8) -> BoxFuture<'_, Result<bool, Error>> {
+        Box::pin(async move {
+            let client = self.client().await?;
+            Ok(client
+                .execute("UPDATE city SET monitor = $1 WHERE id = $2", &[&i16::from(monitor), &i32::from(city_id)])
+                .await?
+                > 0)
+        })
+    }
+
+    fn delete_expired<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        before: i64,
+        limit: u64,
+        archive: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        Box::pin(async move {
+            let Some((column, before)) = self.profile.timestamp(table, column, before) else {
+                return Ok(0);
+            };
+            // there is no DELETE ... LIMIT, go through the physical row ids
+            let delete = format!(
+                "DELETE FROM \"{}\" WHERE ctid IN (SELECT ctid FROM \"{}\" WHERE \"{}\" < $1 LIMIT {})",
+                table, table, column, limit
+            );
+            let query = match archive {
+                Some(archive) => format!(
+                    "WITH moved AS ({} RETURNING *), archived AS (INSERT INTO \"{}\" SELECT * FROM moved ON CONFLICT DO NOTHING) SELECT COUNT(*) FROM moved",
+                    delete, archive
+                ),
+                None => format!("WITH moved AS ({} RETURNING 1) SELECT COUNT(*) FROM moved", delete),
+            };
+
+            let client = self.client().await?;
+            let deleted: i64 = client.query_one(&query, &[&before]).await?.try_get(0)?;
+            Ok(u64::try_from(deleted)?)
+        })
+    }
+
+    fn clear_expired<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        columns: &'a [&'a str],
+        before: i64,
+        limit: u64,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        Box::pin(async move {
+            let Some((column, before)) = self.profile.timestamp(table, column, before) else {
+                return Ok(0);
+            };
+            let assignments = self
+                .profile
+                .columns(table, columns)
+                .iter()
+                .map(|name| format!("\"{}\" = NULL", name))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            let client = self.client().await?;
+            Ok(client
+                .execute(
+                    &format!(
+                        "UPDATE \"{}\" SET {} WHERE ctid IN (SELECT ctid FROM \"{}\" WHERE \"{}\" < $1 LIMIT {})",
+                        table, assignments, table, column, limit
+                    ),
+                    &[&before],
+                )
+                .await?)
+        })
+    }
+}
@@ -0,0 +1,393 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use chrono::NaiveDate;
+
+use futures_util::future::BoxFuture;
+
+use rusqlite::{
+    params_from_iter,
+    types::{ToSqlOutput, Value as SqliteValue},
+    Connection, Row, ToSql,
+};
+
+use tracing::warn;
+
+use super::{invalid_city, rollups, CityStats, Error, PokemonStat, Storage, CITY_STATS};
+
+use crate::{
+    config::{Pool as PoolConfig, Profile},
+    lists::City,
+    migrations::Migration,
+    pvp::Leagues,
+    upsert::{Dialect, Upsert, Value},
+};
+
+pub struct Sqlite {
+    // SQLite serializes writes anyway, a single connection is enough
+    conn: Arc<Mutex<Connection>>,
+    // milliseconds waited for the connection before logging
+    slow_acquire: u64,
+    profile: Profile,
+}
+
+impl Sqlite {
+    pub fn new(path: &str, statement_cache: usize, profile: Profile, config: &PoolConfig) -> Result<Self, Error> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA busy_timeout = 5000;")?;
+        conn.set_prepared_statement_cache_capacity(statement_cache);
+        if let Some(timeout) = config.acquire_timeout {
+            conn.busy_timeout(Duration::from_secs(timeout))?;
+        }
+        Ok(Sqlite { conn: Arc::new(Mutex::new(conn)), slow_acquire: config.slow_acquire.unwrap_or(500), profile })
+    }
+
+    async fn with_conn<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, Error> + Send + 'static,
+    {
+        let conn = Arc::clone(&self.conn);
+        let slow_acquire = Duration::from_millis(self.slow_acquire);
+        tokio::task::spawn_blocking(move || {
+            let start = Instant::now();
+            let mut conn = conn.lock().map_err(|e| e.to_string())?;
+            if start.elapsed() >= slow_acquire {
+                warn!("slow connection acquire: {}ms", start.elapsed().as_millis());
+            }
+            f(&mut conn)
+        })
+        .await?
+    }
+}
+
+impl ToSql for Value {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::Owned(match self {
+            Value::Null => SqliteValue::Null,
+            Value::Bool(b) => SqliteValue::Integer(i64::from(*b)),
+            Value::Int(i) => SqliteValue::Integer(*i),
+            // unsigned 64 bits ids are stored bit by bit
+            Value::UInt(u) => SqliteValue::Integer(*u as i64),
+            Value::Float(f) => SqliteValue::Real(*f),
+            Value::Text(s) => SqliteValue::Text(s.clone()),
+            Value::Date(d) => SqliteValue::Text(d.format("%Y-%m-%d").to_string()),
+            Value::DateTime(d) => SqliteValue::Text(d.format("%Y-%m-%d %H:%M:%S").to_string()),
+        }))
+    }
+}
+
+fn city(row: &Row) -> Result<City, Error> {
+    Ok(City::new(
+        row.get("id")?,
+        row.get("name")?,
+        &row.get::<_, String>("coordinates")?,
+        row.get("scadenza")?,
+        row.get("monitor")?,
+        &row.get::<_, String>("admins_users")?,
+        row.get::<_, Option<String>>("timezone")?.as_deref(),
+    )?)
+}
+
+impl Storage for Sqlite {
+    fn dialect(&self) -> Dialect {
+        Dialect::Sqlite
+    }
+
+    fn schema_version(&self) -> BoxFuture<'_, Result<Option<u32>, Error>> {
+        Box::pin(self.with_conn(|conn| {
+            let managed: bool = conn.query_row(
+                "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'",
+                [],
+                |row| row.get(0),
+            )?;
+            if !managed {
+                return Ok(None);
+            }
+            Ok(Some(conn.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_version", [], |row| row.get(0))?))
+        }))
+    }
+
+    fn table_columns<'a>(&'a self, table: &'a str) -> BoxFuture<'a, Result<Vec<String>, Error>> {
+        let table = table.to_owned();
+        Box::pin(self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached("SELECT name FROM pragma_table_info(?1)")?;
+            let columns = stmt.query_map([table], |row| row.get(0))?.collect::<Result<Vec<_>, _>>()?;
+            Ok(columns)
+        }))
+    }
+
+    fn apply_migration(&self, migration: &'static Migration, applied_at: i64) -> BoxFuture<'_, Result<(), Error>> {
+        let sql = migration.sql(self.profile);
+        Box::pin(self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute_batch(&sql)?;
+            tx.execute(
+                "INSERT INTO schema_version (version, name, applied_at) VALUES (?, ?, ?)",
+                rusqlite::params![migration.version, migration.name, applied_at],
+            )?;
+            tx.commit()?;
+            Ok(())
+        }))
+    }
+
+    fn upsert(&self, upsert: Upsert) -> BoxFuture<'_, Result<u64, Error>> {
+        Box::pin(async move {
+            let (query, params) = upsert.build(Dialect::Sqlite, self.profile);
+            self.with_conn(
+                move |conn| Ok(conn.prepare_cached(&query)?.execute(params_from_iter(params.iter()))? as u64),
+            )
+            .await
+        })
+    }
+
+    fn replace_pokemon_pvp<'a>(&'a self, encounter_id: &'a str, pvp: &'a Leagues) -> BoxFuture<'a, Result<(), Error>> {
+        let encounter_id = encounter_id.to_owned();
+        let rows: Vec<[Value; 8]> = pvp
+            .iter()
+            .flat_map(|(league, rankings)| {
+                rankings.iter().map(|ranking| {
+                    [
+                        encounter_id.as_str().into(),
+                        league.as_str().into(),
+                        ranking.pokemon.into(),
+                        ranking.form.unwrap_or_default().into(),
+                        ranking.rank.into(),
+                        ranking.cp.into(),
+                        ranking.level.into(),
+                        ranking.percentage.into(),
+                    ]
+                })
+            })
+            .collect();
+        Box::pin(self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM pokemon_pvp WHERE encounter_id = ?", [&encounter_id])?;
+            {
+                let mut insert = tx.prepare(
+                    "INSERT INTO pokemon_pvp (encounter_id, league, pokemon_id, form, rank, cp, level, percentage)
+                    VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                )?;
+                for row in rows {
+                    insert.execute(params_from_iter(row.iter()))?;
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        }))
+    }
+
+    fn create_partition<'a>(&'a self, table: &'a str, partition: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        let (table, partition) = (table.to_owned(), partition.to_owned());
+        Box::pin(self.with_conn(move |conn| {
+            // there is no CREATE TABLE ... LIKE, reuse the definition of the template table
+            let sql: String =
+                conn.query_row("SELECT sql FROM sqlite_master WHERE type = 'table' AND name = ?", [&table], |row| {
+                    row.get(0)
+                })?;
+            let columns = sql.find('(').map(|index| &sql[index..]).ok_or("invalid table definition")?;
+            conn.execute_batch(&format!("CREATE TABLE IF NOT EXISTS \"{}\" {}", partition, columns))?;
+            Ok(())
+        }))
+    }
+
+    fn add_pokemon_stats<'a>(&'a self, stats: &'a [PokemonStat]) -> BoxFuture<'a, Result<(), Error>> {
+        let Some(first) = stats.first() else {
+            return Box::pin(async { Ok(()) });
+        };
+        let (table, column) = (first.period.table(), first.period.column());
+        let query = format!(
+            "INSERT INTO {} ({}, pokemon_id, form, city_id, count) VALUES {}
+            ON CONFLICT ({}, pokemon_id, form, city_id) DO UPDATE SET count = count + excluded.count",
+            table,
+            column,
+            vec!["(?, ?, ?, ?, ?)"; stats.len()].join(", "),
+            column
+        );
+        let params: Vec<Value> = stats.iter().flat_map(PokemonStat::values).collect();
+        Box::pin(self.with_conn(move |conn| {
+            conn.execute(&query, params_from_iter(params.iter()))?;
+            Ok(())
+        }))
+    }
+
+    fn uncompacted_stats_days(&self, before: NaiveDate) -> BoxFuture<'_, Result<Vec<NaiveDate>, Error>> {
+        Box::pin(self.with_conn(move |conn| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT DISTINCT date FROM pokemon_stats WHERE date < ?1 AND date NOT IN (SELECT day FROM stats_compacted) ORDER BY date",
+            )?;
+            // dates are stored as text
+            let days = stmt
+                .query_map([Value::from(before)], |row| row.get::<_, String>(0))?
+                .map(|day| Ok(NaiveDate::parse_from_str(&day?, "%Y-%m-%d")?))
+                .collect::<Result<Vec<_>, Error>>()?;
+            Ok(days)
+        }))
+    }
+
+    fn compact_stats_day(&self, day: NaiveDate) -> BoxFuture<'_, Result<bool, Error>> {
+        Box::pin(self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let day_value = Value::from(day);
+            if tx.execute("INSERT INTO stats_compacted (day) VALUES (?1) ON CONFLICT DO NOTHING", [&day_value])? == 0 {
+                return Ok(false);
+            }
+            for (table, column, start) in rollups(day) {
+                tx.execute(
+                    &format!(
+                        "INSERT INTO {} ({}, pokemon_id, form, city_id, count)
+                        SELECT ?1, pokemon_id, form, city_id, SUM(count) FROM pokemon_stats WHERE date = ?2 GROUP BY pokemon_id, form, city_id
+                        ON CONFLICT ({}, pokemon_id, form, city_id) DO UPDATE SET count = count + excluded.count",
+                        table, column, column
+                    ),
+                    params_from_iter([Value::from(start), day_value.clone()].iter()),
+                )?;
+            }
+            tx.commit()?;
+            Ok(true)
+        }))
+    }
+
+    fn prune_stats_days(&self, before: NaiveDate) -> BoxFuture<'_, Result<u64, Error>> {
+        Box::pin(self.with_conn(move |conn| {
+            let deleted = conn.execute(
+                "DELETE FROM pokemon_stats WHERE date < ?1 AND date IN (SELECT day FROM stats_compacted)",
+                [Value::from(before)],
+            )?;
+            Ok(deleted as u64)
+        }))
+    }
+
+    fn prune_city_stats_days(&self, before: NaiveDate) -> BoxFuture<'_, Result<u64, Error>> {
+        Box::pin(self.with_conn(move |conn| {
+            let mut deleted = 0;
+            for table in CITY_STATS {
+                deleted += conn.execute(&format!("DELETE FROM {} WHERE day < ?1", table), [Value::from(before)])?;
+            }
+            Ok(deleted as u64)
+        }))
+    }
+
+    fn update_city_lure_stats(&self, day: NaiveDate, city_id: u16, lure_id: u16) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO city_lure_stats (day, city_id, lure_id, count) VALUES (?, ?, ?, 1)
+                ON CONFLICT (day, city_id, lure_id) DO UPDATE SET count = count + 1",
+                params_from_iter([Value::from(day), Value::from(city_id), Value::from(lure_id)].iter()),
+            )?;
+            Ok(())
+        }))
+    }
+
+    fn load_cities(&self) -> BoxFuture<'_, Result<Vec<Result<City, String>>, Error>> {
+        Box::pin(self.with_conn(|conn| {
+            let mut stmt =
+                conn.prepare("SELECT id, name, coordinates, scadenza, monitor, admins_users, timezone FROM city")?;
+            let cities = stmt
+                .query_map([], |row| Ok(city(row).map_err(|e| invalid_city(row.get("id").ok(), e))))?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(cities)
+        }))
+    }
+
+    fn city_stats(&self, city_id: u16, day: NaiveDate) -> BoxFuture<'_, Result<CityStats, Error>> {
+        Box::pin(self.with_conn(move |conn| {
+            let params = [Value::from(day), Value::from(city_id)];
+            let mut stats = CityStats::default();
+            let mut stmt = conn.prepare_cached(
+                "SELECT pokemon_id, COUNT(*) FROM city_stats_today WHERE day = ?1 AND city_id = ?2 GROUP BY pokemon_id",
+            )?;
+            for row in stmt.query_map(params_from_iter(params.iter()), |row| Ok((row.get(0)?, row.get(1)?)))? {
+                let (pokemon_id, count) = row?;
+                stats.pokemon.insert(pokemon_id, count);
+            }
+            let mut stmt =
+                conn.prepare_cached("SELECT lure_id, count FROM city_lure_stats WHERE day = ?1 AND city_id = ?2")?;
+            for row in stmt.query_map(params_from_iter(params.iter()), |row| Ok((row.get(0)?, row.get(1)?)))? {
+                let (lure_id, count) = row?;
+                stats.lures.insert(lure_id, count);
+            }
+            let mut stmt = conn.prepare_cached(
+                "SELECT kind, value, COUNT(*) FROM city_entity_stats WHERE day = ?1 AND city_id = ?2 GROUP BY kind, value",
+            )?;
+            let mut rows = stmt.query(params_from_iter(params.iter()))?;
+            while let Some(row) = rows.next()? {
+                stats.add_entities(&row.get::<_, String>(0)?, row.get(1)?, row.get(2)?);
+            }
+            Ok(stats)
+        }))
+    }
+
+    fn update_city_coordinates<'a>(
+        &'a self,
+        city_id: u16,
+        text: &'a str,
+        _wkt: &'a str,
+    ) -> BoxFuture<'a, Result<bool, Error>> {
+        // SQLite has no spatial columns
+        let text = text.to_owned();
+        Box::pin(self.with_conn(move |conn| {
+            Ok(conn.execute("UPDATE city SET coordinates = ?1 WHERE id = ?2", rusqlite::params![text, city_id])? > 0)
+        }))
+    }
+
+    fn update_city_monitor(&self, city_id: u16, monitor: u8) -> BoxFuture<'_, Result<bool, Error>> {
+        Box::pin(self.with_conn(move |conn| {
+            Ok(conn.execute("UPDATE city SET monitor = ?1 WHERE id = ?2", rusqlite::params![monitor, city_id])? > 0)
+        }))
+    }
+
+    fn delete_expired<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        before: i64,
+        limit: u64,
+        archive: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        let Some((column, before)) = self.profile.timestamp(table, column, before) else {
+            return Box::pin(async { Ok(0) });
+        };
+        let rows = format!("SELECT rowid FROM \"{}\" WHERE \"{}\" < ?1 LIMIT {}", table, column, limit);
+        let delete = format!("DELETE FROM \"{}\" WHERE rowid IN ({})", table, rows);
+        let archive = archive.map(|archive| {
+            format!("INSERT OR IGNORE INTO \"{}\" SELECT * FROM \"{}\" WHERE rowid IN ({})", archive, table, rows)
+        });
+        Box::pin(self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            if let Some(archive) = archive {
+                tx.execute(&archive, [&before])?;
+            }
+            let deleted = tx.execute(&delete, [&before])?;
+            tx.commit()?;
+            Ok(deleted as u64)
+        }))
+    }
+
+    fn clear_expired<'a>(
+        &'a self,
+        table: &'a str,
+        column: &'a str,
+        columns: &'a [&'a str],
+        before: i64,
+        limit: u64,
+    ) -> BoxFuture<'a, Result<u64, Error>> {
+        let Some((column, before)) = self.profile.timestamp(table, column, before) else {
+            return Box::pin(async { Ok(0) });
+        };
+        let assignments = self
+            .profile
+            .columns(table, columns)
+            .iter()
+            .map(|name| format!("\"{}\" = NULL", name))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let query = format!(
+            "UPDATE \"{}\" SET {} WHERE rowid IN (SELECT rowid FROM \"{}\" WHERE \"{}\" < ? LIMIT {})",
+            table, assignments, table, column, limit
+        );
+        Box::pin(self.with_conn(move |conn| Ok(conn.execute(&query, [&before])? as u64)))
+    }
+}
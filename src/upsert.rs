@@ -0,0 +1,406 @@
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+
+use crate::{
+    config::{Merge, MergePolicy, Profile, CONFIG},
+    profile::Field,
+};
+
+/// Backend-agnostic query parameter
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Float(f64),
+    Text(String),
+    Date(NaiveDate),
+    DateTime(NaiveDateTime),
+}
+
+macro_rules! impl_from {
+    ($variant:ident: $($t:ty),+) => {
+        $(impl From<$t> for Value {
+            fn from(v: $t) -> Self {
+                Value::$variant(v.into())
+            }
+        })+
+    };
+}
+
+impl_from!(Bool: bool);
+impl_from!(Int: i8, i16, i32, i64, u8, u16, u32);
+impl_from!(UInt: u64);
+impl_from!(Float: f32, f64);
+impl_from!(Text: &str, String);
+impl_from!(Date: NaiveDate);
+impl_from!(DateTime: NaiveDateTime);
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Self {
+        v.map(Into::into).unwrap_or(Value::Null)
+    }
+}
+
+impl Value {
+    /// Unix timestamps become UTC datetimes, anything else is left as is
+    pub fn into_datetime(self) -> Self {
+        let timestamp = match self {
+            Value::Int(i) => i,
+            Value::UInt(u) => u as i64,
+            other => return other,
+        };
+        DateTime::from_timestamp(timestamp, 0).map(|d| Value::DateTime(d.naive_utc())).unwrap_or(Value::Null)
+    }
+}
+
+/// SQL flavour an upsert is rendered to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dialect {
+    Mysql,
+    Postgres,
+    Sqlite,
+}
+
+#[derive(Clone)]
+struct Column {
+    name: String,
+    key: bool,
+    value: Value,
+    // value written on insert when it differs from the one used for merging
+    insert: Option<Value>,
+    policy: MergePolicy,
+}
+
+/// Builds an upsert query applying a merge policy to every column.
+///
+/// Every column comes with a default policy, that can be overridden per table in the `merge` config section.
+#[derive(Clone)]
+pub struct Upsert {
+    table: String,
+    merge: Option<&'static Merge>,
+    version: Option<String>,
+    columns: Vec<Column>,
+}
+
+impl Upsert {
+    pub fn new(table: impl Into<String>) -> Self {
+        let table = table.into();
+        let merge = CONFIG.merge.get(&table);
+        Self::with_merge(table, merge)
+    }
+
+    fn with_merge(table: String, merge: Option<&'static Merge>) -> Self {
+        let version = merge.and_then(|merge| merge.version.clone());
+        Upsert { table, merge, version, columns: Vec::new() }
+    }
+
+    /// Column compared by `if_newer` policies, unless the config says otherwise
+    pub fn version(mut self, name: &str) -> Self {
+        if self.version.is_none() {
+            self.version = Some(name.to_owned());
+        }
+        self
+    }
+
+    /// Key columns are only written on insert, whatever the config says
+    pub fn key(mut self, name: &str, value: impl Into<Value>) -> Self {
+        self.columns.push(Column {
+            name: name.to_owned(),
+            key: true,
+            value: value.into(),
+            insert: None,
+            policy: MergePolicy::Never,
+        });
+        self
+    }
+
+    pub fn column(self, name: impl Into<String>, value: impl Into<Value>, policy: MergePolicy) -> Self {
+        self.push(name.into(), value.into(), None, policy)
+    }
+
+    /// Like `column`, but inserts `fallback` when `value` is null
+    pub fn column_or(
+        self,
+        name: impl Into<String>,
+        value: impl Into<Value>,
+        fallback: impl Into<Value>,
+        policy: MergePolicy,
+    ) -> Self {
+        let value = value.into();
+        let insert = (value == Value::Null).then(|| fallback.into());
+        self.push(name.into(), value, insert, policy)
+    }
+
+    fn push(mut self, name: String, value: Value, insert: Option<Value>, policy: MergePolicy) -> Self {
+        let policy = self.merge.and_then(|merge| merge.columns.get(&name)).copied().unwrap_or(policy);
+        self.columns.push(Column { name, key: false, value, insert, policy });
+        self
+    }
+
+    // renames columns following the configured schema profile, dropping the ones it doesn't have
+    fn apply_profile(mut self, profile: Profile) -> Self {
+        let table = self.table.clone();
+        self.version = self.version.and_then(|name| match profile.field(&table, &name) {
+            Field::Missing => None,
+            Field::Column(name) | Field::Datetime(name) => Some(name.to_owned()),
+        });
+        self.columns = self
+            .columns
+            .into_iter()
+            .filter_map(|c| match profile.field(&table, &c.name) {
+                Field::Missing => None,
+                Field::Column(name) => Some(Column { name: name.to_owned(), ..c }),
+                Field::Datetime(name) => Some(Column {
+                    name: name.to_owned(),
+                    value: c.value.into_datetime(),
+                    insert: c.insert.map(Value::into_datetime),
+                    ..c
+                }),
+            })
+            .collect();
+        self
+    }
+
+    pub fn build(self, dialect: Dialect, profile: Profile) -> (String, Vec<Value>) {
+        self.apply_profile(profile).render(dialect)
+    }
+
+    fn render(self, dialect: Dialect) -> (String, Vec<Value>) {
+        let mut params: Vec<Value> = Vec::new();
+        let mut placeholder = |value: &Value| {
+            params.push(value.clone());
+            match dialect {
+                Dialect::Mysql | Dialect::Sqlite => String::from("?"),
+                Dialect::Postgres => format!("${}", params.len()),
+            }
+        };
+        // MySQL and SQLite read the stored row through plain column names, PostgreSQL needs them qualified
+        let stored = |name: &str| match dialect {
+            Dialect::Mysql | Dialect::Sqlite => name.to_owned(),
+            Dialect::Postgres => format!("{}.{}", self.table, name),
+        };
+
+        let names = self.columns.iter().map(|c| c.name.as_str()).collect::<Vec<_>>().join(", ");
+        let values = self
+            .columns
+            .iter()
+            .map(|c| placeholder(c.insert.as_ref().unwrap_or(&c.value)))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        // MySQL applies assignments from left to right, the version column must be the last one
+        // or the following comparisons would see the incoming value
+        let version = self.version.as_deref().and_then(|name| self.columns.iter().find(|c| c.name == name));
+        let mut assignments = Vec::new();
+        for c in self.columns.iter().filter(|c| self.version.as_deref() != Some(c.name.as_str())).chain(version) {
+            match (c.policy, version) {
+                (MergePolicy::Never, _) => {}
+                // without a version column there is nothing to compare to
+                (MergePolicy::Always, _) | (MergePolicy::IfNewer, None) => {
+                    assignments.push(format!("{} = {}", c.name, placeholder(&c.value)));
+                }
+                (MergePolicy::IfNotNull, _) => {
+                    assignments.push(format!("{} = COALESCE({}, {})", c.name, placeholder(&c.value), stored(&c.name)));
+                }
+                // a stored row without version is older than anything, whatever type the version has
+                (MergePolicy::IfNewer, Some(version)) => {
+                    assignments.push(format!(
+                        "{} = CASE WHEN {} IS NULL OR {} >= {} THEN {} ELSE {} END",
+                        c.name,
+                        stored(&version.name),
+                        placeholder(&version.value),
+                        stored(&version.name),
+                        placeholder(&c.value),
+                        stored(&c.name)
+                    ));
+                }
+            }
+        }
+
+        let query = match dialect {
+            // a no-op assignment rather than INSERT IGNORE, which would also hide strict mode errors
+            Dialect::Mysql if assignments.is_empty() => {
+                let key = self.columns.iter().find(|c| c.key).or(self.columns.first()).map_or("", |c| c.name.as_str());
+                format!(
+                    "INSERT INTO {} ({}) VALUES ({}) ON DUPLICATE KEY UPDATE {} = {}",
+                    self.table, names, values, key, key
+                )
+            }
+            Dialect::Mysql => format!(
+                "INSERT INTO {} ({}) VALUES ({}) ON DUPLICATE KEY UPDATE {}",
+                self.table,
+                names,
+                values,
+                assignments.join(", ")
+            ),
+            Dialect::Postgres | Dialect::Sqlite if assignments.is_empty() => {
+                format!("INSERT INTO {} ({}) VALUES ({}) ON CONFLICT DO NOTHING", self.table, names, values)
+            }
+            Dialect::Postgres | Dialect::Sqlite => format!(
+                "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT ({}) DO UPDATE SET {}",
+                self.table,
+                names,
+                values,
+                self.columns.iter().filter(|c| c.key).map(|c| c.name.as_str()).collect::<Vec<_>>().join(", "),
+                assignments.join(", ")
+            ),
+        };
+        (query, params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn if_newer() -> Upsert {
+        Upsert::with_merge("pokemon".to_owned(), None)
+            .version("updated")
+            .key("id", 1)
+            .column("cp", 500, MergePolicy::IfNewer)
+            .column("updated", 10, MergePolicy::IfNewer)
+    }
+
+    #[test]
+    fn if_newer_mysql() {
+        let (query, params) = if_newer().render(Dialect::Mysql);
+        assert_eq!(
+            query,
+            "INSERT INTO pokemon (id, cp, updated) VALUES (?, ?, ?) ON DUPLICATE KEY UPDATE \
+             cp = CASE WHEN updated IS NULL OR ? >= updated THEN ? ELSE cp END, \
+             updated = CASE WHEN updated IS NULL OR ? >= updated THEN ? ELSE updated END"
+        );
+        assert_eq!(params, [1, 500, 10, 10, 500, 10, 10].map(Value::from));
+    }
+
+    #[test]
+    fn if_newer_postgres() {
+        let (query, params) = if_newer().render(Dialect::Postgres);
+        assert_eq!(
+            query,
+            "INSERT INTO pokemon (id, cp, updated) VALUES ($1, $2, $3) ON CONFLICT (id) DO UPDATE SET \
+             cp = CASE WHEN pokemon.updated IS NULL OR $4 >= pokemon.updated THEN $5 ELSE pokemon.cp END, \
+             updated = CASE WHEN pokemon.updated IS NULL OR $6 >= pokemon.updated THEN $7 ELSE pokemon.updated END"
+        );
+        assert_eq!(params, [1, 500, 10, 10, 500, 10, 10].map(Value::from));
+    }
+
+    #[test]
+    fn if_newer_sqlite() {
+        let (query, params) = if_newer().render(Dialect::Sqlite);
+        assert_eq!(
+            query,
+            "INSERT INTO pokemon (id, cp, updated) VALUES (?, ?, ?) ON CONFLICT (id) DO UPDATE SET \
+             cp = CASE WHEN updated IS NULL OR ? >= updated THEN ? ELSE cp END, \
+             updated = CASE WHEN updated IS NULL OR ? >= updated THEN ? ELSE updated END"
+        );
+        assert_eq!(params, [1, 500, 10, 10, 500, 10, 10].map(Value::from));
+    }
+
+    #[test]
+    fn if_newer_without_version() {
+        let (query, _) = Upsert::with_merge("pokemon".to_owned(), None)
+            .key("id", 1)
+            .column("cp", 500, MergePolicy::IfNewer)
+            .render(Dialect::Sqlite);
+        assert_eq!(query, "INSERT INTO pokemon (id, cp) VALUES (?, ?) ON CONFLICT (id) DO UPDATE SET cp = ?");
+    }
+
+    // one column per policy, the version column comes first to check it's moved last
+    fn policies(merge: Option<&'static Merge>) -> Upsert {
+        Upsert::with_merge("gym".to_owned(), merge)
+            .version("updated")
+            .key("id", "a")
+            .column("updated", 10, MergePolicy::Always)
+            .column("name", "x", MergePolicy::IfNotNull)
+            .column("team_id", 1, MergePolicy::Always)
+            .column("first_seen_timestamp", 5, MergePolicy::Never)
+            .column("cp", 7, MergePolicy::IfNewer)
+    }
+
+    fn policy_params() -> Vec<Value> {
+        let values: [Value; 6] = ["a".into(), 10.into(), "x".into(), 1.into(), 5.into(), 7.into()];
+        let assignments: [Value; 5] = ["x".into(), 1.into(), 10.into(), 7.into(), 10.into()];
+        values.into_iter().chain(assignments).collect()
+    }
+
+    #[test]
+    fn policies_mysql() {
+        let (query, params) = policies(None).render(Dialect::Mysql);
+        assert_eq!(
+            query,
+            "INSERT INTO gym (id, updated, name, team_id, first_seen_timestamp, cp) VALUES (?, ?, ?, ?, ?, ?) \
+             ON DUPLICATE KEY UPDATE name = COALESCE(?, name), team_id = ?, \
+             cp = CASE WHEN updated IS NULL OR ? >= updated THEN ? ELSE cp END, updated = ?"
+        );
+        assert_eq!(params, policy_params());
+    }
+
+    #[test]
+    fn policies_postgres() {
+        let (query, params) = policies(None).render(Dialect::Postgres);
+        assert_eq!(
+            query,
+            "INSERT INTO gym (id, updated, name, team_id, first_seen_timestamp, cp) VALUES ($1, $2, $3, $4, $5, $6) \
+             ON CONFLICT (id) DO UPDATE SET name = COALESCE($7, gym.name), team_id = $8, \
+             cp = CASE WHEN gym.updated IS NULL OR $9 >= gym.updated THEN $10 ELSE gym.cp END, updated = $11"
+        );
+        assert_eq!(params, policy_params());
+    }
+
+    #[test]
+    fn policies_sqlite() {
+        let (query, params) = policies(None).render(Dialect::Sqlite);
+        assert_eq!(
+            query,
+            "INSERT INTO gym (id, updated, name, team_id, first_seen_timestamp, cp) VALUES (?, ?, ?, ?, ?, ?) \
+             ON CONFLICT (id) DO UPDATE SET name = COALESCE(?, name), team_id = ?, \
+             cp = CASE WHEN updated IS NULL OR ? >= updated THEN ? ELSE cp END, updated = ?"
+        );
+        assert_eq!(params, policy_params());
+    }
+
+    #[test]
+    fn never_only() {
+        let upsert = Upsert::with_merge("gym".to_owned(), None).key("id", "a").column("name", "x", MergePolicy::Never);
+        assert_eq!(
+            upsert.clone().render(Dialect::Mysql).0,
+            "INSERT INTO gym (id, name) VALUES (?, ?) ON DUPLICATE KEY UPDATE id = id"
+        );
+        assert_eq!(
+            upsert.clone().render(Dialect::Postgres).0,
+            "INSERT INTO gym (id, name) VALUES ($1, $2) ON CONFLICT DO NOTHING"
+        );
+        assert_eq!(upsert.render(Dialect::Sqlite).0, "INSERT INTO gym (id, name) VALUES (?, ?) ON CONFLICT DO NOTHING");
+    }
+
+    #[test]
+    fn column_or_inserts_fallback() {
+        let (query, params) = Upsert::with_merge("gym".to_owned(), None)
+            .key("id", "a")
+            .column_or("name", None::<&str>, "unknown", MergePolicy::IfNotNull)
+            .render(Dialect::Sqlite);
+        assert_eq!(
+            query,
+            "INSERT INTO gym (id, name) VALUES (?, ?) ON CONFLICT (id) DO UPDATE SET name = COALESCE(?, name)"
+        );
+        assert_eq!(params, [Value::from("a"), Value::from("unknown"), Value::Null]);
+    }
+
+    #[test]
+    fn merge_overrides() {
+        let merge = Box::leak(Box::new(Merge {
+            version: Some("last_modified_timestamp".to_owned()),
+            columns: [("team_id".to_owned(), MergePolicy::Never), ("cp".to_owned(), MergePolicy::Always)].into(),
+        }));
+        let (query, _) =
+            policies(Some(merge)).column("last_modified_timestamp", 3, MergePolicy::Always).render(Dialect::Mysql);
+        assert_eq!(
+            query,
+            "INSERT INTO gym (id, updated, name, team_id, first_seen_timestamp, cp, last_modified_timestamp) \
+             VALUES (?, ?, ?, ?, ?, ?, ?) ON DUPLICATE KEY UPDATE updated = ?, name = COALESCE(?, name), cp = ?, \
+             last_modified_timestamp = ?"
+        );
+    }
+}